@@ -0,0 +1,18 @@
+use xmlsql::ParseOptions;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: stats <path to XML file>");
+
+    let db = xmlsql::parse_path_in_memory(
+        path,
+        ParseOptions {
+            infer_types: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    print!("{}", db.stats().unwrap());
+}
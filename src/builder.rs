@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use crate::{
     document::NodeType,
     infer::{infer_type, Inferred},
@@ -12,6 +14,46 @@ CREATE INDEX IF NOT EXISTS idx_nodes_descendents ON nodes(node_type, parent_node
 CREATE INDEX IF NOT EXISTS idx_attrs_name ON attrs(attr_name);
 "#;
 
+// Interns the label path of every element, walking down from the root. Needs
+// the parent index to be in place.
+const PATHS: &str = r#"
+CREATE TEMP TABLE node_paths (
+    node_id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL,
+    depth INTEGER NOT NULL
+);
+
+INSERT INTO node_paths(node_id, path, depth)
+WITH RECURSIVE tree(node_id, path, depth) AS (
+    SELECT node_id,
+        '/' || CASE WHEN node_ns IS NULL THEN node_name ELSE node_ns || ':' || node_name END,
+        1
+        FROM nodes WHERE node_id = 1 AND node_type = 1
+    UNION ALL
+    SELECT nodes.node_id,
+        tree.path || '/' || CASE WHEN nodes.node_ns IS NULL THEN nodes.node_name ELSE nodes.node_ns || ':' || nodes.node_name END,
+        tree.depth + 1
+        FROM nodes, tree
+        WHERE nodes.parent_node_id = tree.node_id AND nodes.node_type = 1
+)
+SELECT node_id, path, depth FROM tree;
+
+INSERT INTO paths(path, depth)
+SELECT path, depth FROM node_paths GROUP BY path ORDER BY MIN(node_id);
+
+UPDATE nodes SET
+    path_id = (
+        SELECT paths.path_id FROM node_paths, paths
+            WHERE node_paths.node_id = nodes.node_id AND paths.path = node_paths.path
+    ),
+    depth = (SELECT depth FROM node_paths WHERE node_paths.node_id = nodes.node_id)
+    WHERE node_type = 1;
+
+DROP TABLE node_paths;
+
+CREATE INDEX IF NOT EXISTS idx_nodes_path_id ON nodes(path_id);
+"#;
+
 pub(crate) struct DocumentDbBuilder<'a> {
     pub(crate) conn: rusqlite::Transaction<'a>,
     pub(crate) infer_types: bool,
@@ -51,6 +93,10 @@ impl InsertNode {
             node_order,
         }
     }
+
+    pub fn buffer_position(&self) -> usize {
+        self.buffer_position
+    }
 }
 
 pub struct InsertAttr {
@@ -80,6 +126,10 @@ impl InsertAttr {
             attr_order,
         }
     }
+
+    pub fn buffer_position(&self) -> usize {
+        self.buffer_position
+    }
 }
 
 pub struct InsertRootElement {
@@ -103,6 +153,30 @@ impl InsertRootElement {
             node_order,
         }
     }
+
+    pub fn buffer_position(&self) -> usize {
+        self.buffer_position
+    }
+}
+
+pub struct InsertError {
+    parent_node_id: usize,
+    buffer_position: usize,
+    message: String,
+}
+
+impl InsertError {
+    pub fn new(parent_node_id: usize, buffer_position: usize, message: String) -> Self {
+        Self {
+            parent_node_id,
+            buffer_position,
+            message,
+        }
+    }
+
+    pub fn buffer_position(&self) -> usize {
+        self.buffer_position
+    }
 }
 
 impl<'a> DocumentDbBuilder<'a> {
@@ -121,6 +195,11 @@ impl<'a> DocumentDbBuilder<'a> {
         Ok(())
     }
 
+    #[inline(always)]
+    pub fn add_paths(&self) -> rusqlite::Result<()> {
+        add_paths(&self.conn)
+    }
+
     #[inline(always)]
     #[allow(clippy::too_many_arguments)]
     pub fn insert_node(&self, data: InsertNode) -> Result<(), rusqlite::Error> {
@@ -229,6 +308,26 @@ impl<'a> DocumentDbBuilder<'a> {
         Ok(())
     }
 
+    #[inline(always)]
+    pub fn insert_error(&self, data: InsertError) -> Result<(), rusqlite::Error> {
+        let InsertError {
+            parent_node_id,
+            buffer_position,
+            message,
+        } = data;
+
+        let mut stmt = self.conn.prepare_cached(
+            r#"
+            INSERT INTO parse_errors(parent_node_id, buffer_position, message)
+            VALUES (?1, ?2, ?3)
+        "#,
+        )?;
+
+        stmt.execute((parent_node_id, buffer_position, message))?;
+
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn insert_root_element(&self, data: InsertRootElement) -> Result<(), rusqlite::Error> {
         let InsertRootElement {
@@ -252,3 +351,53 @@ impl<'a> DocumentDbBuilder<'a> {
         Ok(())
     }
 }
+
+pub(crate) fn add_paths(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(PATHS)
+}
+
+pub(crate) fn merge_chunk(
+    conn: &rusqlite::Connection,
+    path: &Path,
+    infer_types: bool,
+    id_offset: usize,
+    order_offset: usize,
+) -> Result<(), rusqlite::Error> {
+    let inferred_type = if infer_types { ", inferred_type" } else { "" };
+
+    conn.execute("ATTACH DATABASE ?1 AS chunk", [path.to_string_lossy()])?;
+
+    conn.execute(
+        &format!(
+            r#"
+            INSERT INTO main.nodes(node_id, parent_node_id, node_order, node_type, node_ns, node_name, node_value, buffer_position{inferred_type})
+            SELECT
+                node_id + ?1,
+                CASE WHEN parent_node_id = 1 THEN 1 ELSE parent_node_id + ?1 END,
+                CASE WHEN parent_node_id = 1 THEN node_order + ?2 ELSE node_order END,
+                node_type, node_ns, node_name, node_value, buffer_position{inferred_type}
+            FROM chunk.nodes WHERE node_id > 1 ORDER BY node_id
+        "#
+        ),
+        (id_offset, order_offset),
+    )?;
+
+    conn.execute(
+        &format!(
+            r#"
+            INSERT INTO main.attrs(attr_order, attr_ns, attr_name, attr_value, parent_node_id, buffer_position{inferred_type})
+            SELECT
+                CASE WHEN parent_node_id = 1 THEN attr_order + ?2 ELSE attr_order END,
+                attr_ns, attr_name, attr_value,
+                CASE WHEN parent_node_id = 1 THEN 1 ELSE parent_node_id + ?1 END,
+                buffer_position{inferred_type}
+            FROM chunk.attrs ORDER BY attr_id
+        "#
+        ),
+        (id_offset, order_offset),
+    )?;
+
+    conn.execute("DETACH DATABASE chunk", [])?;
+
+    Ok(())
+}
@@ -0,0 +1,399 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+};
+
+use rusqlite::OptionalExtension;
+
+use crate::{
+    model::{qualified_name, Attr, Element, Node},
+    DocumentDb, Error,
+};
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Options for Canonical XML 1.0 and Exclusive XML Canonicalization output.
+#[derive(Debug, Clone, Default)]
+pub struct C14nConfig {
+    /// Use Exclusive XML Canonicalization instead of Canonical XML 1.0.
+    pub exclusive: bool,
+    pub with_comments: bool,
+    /// The InclusiveNamespaces PrefixList of exclusive canonicalization, with
+    /// `#default` for the default namespace.
+    pub inclusive_prefixes: Vec<String>,
+}
+
+/// Prefix to namespace URI, with the default namespace under `""`.
+type Namespaces = BTreeMap<String, String>;
+
+struct Canonicalizer<'a, W> {
+    db: &'a DocumentDb,
+    config: &'a C14nConfig,
+    f: W,
+    decode: bool,
+}
+
+impl DocumentDb {
+    pub fn c14n_to_string(&self, node_id: usize, config: &C14nConfig) -> rusqlite::Result<String> {
+        let mut s = vec![];
+        match self.write_c14n(node_id, &mut s, config) {
+            Ok(()) => {}
+            Err(Error::Db(e)) => return Err(e),
+            Err(e) => panic!("{e}"),
+        }
+        Ok(String::from_utf8(s).expect("invalid UTF-8"))
+    }
+
+    /// Writes the canonical form of the document when `node_id` is 0, or of
+    /// the subtree at `node_id` with the namespace context of its ancestors.
+    pub fn write_c14n<W: Write>(
+        &self,
+        node_id: usize,
+        f: W,
+        config: &C14nConfig,
+    ) -> Result<(), Error> {
+        let mut c = Canonicalizer {
+            db: self,
+            config,
+            f,
+            decode: self.options.raw_values(),
+        };
+
+        if node_id == 0 {
+            return c.document();
+        }
+
+        match self.node(node_id)? {
+            Node::Element(element) => {
+                let (scope, xml_attrs) = c.ancestor_scope(node_id)?;
+                c.element(&element, &scope, &Namespaces::new(), xml_attrs)
+            }
+            node => c.leaf(&node),
+        }
+    }
+}
+
+impl<W: Write> Canonicalizer<'_, W> {
+    fn document(&mut self) -> Result<(), Error> {
+        let mut after_root = false;
+
+        for node in self.db.child_nodes(0)? {
+            match &node {
+                Node::Element(element) => {
+                    self.element(element, &Namespaces::new(), &Namespaces::new(), vec![])?;
+                    after_root = true;
+                }
+                Node::Comment(_) if !self.config.with_comments => {}
+                Node::Comment(_) | Node::ProcessingInstruction(_) => {
+                    if after_root {
+                        writeln!(self.f)?;
+                    }
+                    self.leaf(&node)?;
+                    if !after_root {
+                        writeln!(self.f)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The namespaces declared by the ancestors of an element, and for
+    /// inclusive canonicalization the `xml:*` attributes it inherits.
+    fn ancestor_scope(&self, node_id: usize) -> Result<(Namespaces, Vec<Attr>), Error> {
+        let mut ancestors = vec![];
+        let mut current = node_id;
+        while let Some(parent) = self.db.parent_element_id(current).optional()? {
+            ancestors.push(parent);
+            current = parent;
+        }
+
+        let mut scope = Namespaces::new();
+        let mut xml_attrs: Vec<Attr> = vec![];
+
+        for id in ancestors.into_iter().rev() {
+            for attr in self.db.attrs(id)? {
+                if let Some(prefix) = ns_decl_prefix(&attr) {
+                    let value = self.attr_value(&attr.value).into_owned();
+                    scope.insert(prefix.to_string(), value);
+                } else if attr.ns.as_deref() == Some("xml") {
+                    xml_attrs.retain(|x| x.name != attr.name);
+                    xml_attrs.push(attr);
+                }
+            }
+        }
+
+        if self.config.exclusive {
+            xml_attrs.clear();
+        }
+
+        Ok((scope, xml_attrs))
+    }
+
+    fn element(
+        &mut self,
+        element: &Element,
+        parent_scope: &Namespaces,
+        rendered: &Namespaces,
+        inherited_attrs: Vec<Attr>,
+    ) -> Result<(), Error> {
+        let mut scope = parent_scope.clone();
+        let mut attrs = vec![];
+
+        for attr in self.db.attrs(element.node_id)? {
+            match ns_decl_prefix(&attr) {
+                Some(prefix) => {
+                    let value = self.attr_value(&attr.value).into_owned();
+                    scope.insert(prefix.to_string(), value);
+                }
+                None => attrs.push(attr),
+            }
+        }
+
+        for attr in inherited_attrs {
+            if !attrs
+                .iter()
+                .any(|x| x.ns.as_deref() == Some("xml") && x.name == attr.name)
+            {
+                attrs.push(attr);
+            }
+        }
+
+        let prefixes = if self.config.exclusive {
+            // Only namespaces visibly used by the element and its attributes.
+            let mut prefixes = BTreeSet::new();
+            prefixes.insert(element.ns.clone().unwrap_or_default());
+            prefixes.extend(attrs.iter().filter_map(|x| x.ns.clone()));
+            for prefix in &self.config.inclusive_prefixes {
+                let prefix = if prefix == "#default" { "" } else { prefix };
+                if scope.contains_key(prefix) {
+                    prefixes.insert(prefix.to_string());
+                }
+            }
+            prefixes
+        } else {
+            scope.keys().cloned().collect()
+        };
+
+        let mut now_rendered = rendered.clone();
+        let mut decls = Namespaces::new();
+
+        for prefix in prefixes {
+            if prefix == "xml" {
+                continue;
+            }
+
+            let uri = scope.get(&prefix).map(String::as_str).unwrap_or_default();
+            let is_rendered = rendered.get(&prefix).map(String::as_str);
+
+            // Only the default namespace can be undeclared, and only when an
+            // output ancestor declared it.
+            let needed = if uri.is_empty() {
+                prefix.is_empty() && is_rendered.is_some_and(|x| !x.is_empty())
+            } else {
+                is_rendered != Some(uri)
+            };
+
+            if needed {
+                decls.insert(prefix.clone(), uri.to_string());
+                now_rendered.insert(prefix, uri.to_string());
+            }
+        }
+
+        attrs.sort_by_cached_key(|x| {
+            let uri = match x.ns.as_deref() {
+                None => "",
+                Some("xml") => XML_NS,
+                Some(prefix) => scope.get(prefix).map(String::as_str).unwrap_or_default(),
+            };
+            (uri.to_string(), x.name.clone())
+        });
+
+        let name = qualified_name(element.ns.as_deref(), &element.name);
+        write!(self.f, "<{name}")?;
+
+        for (prefix, uri) in &decls {
+            if prefix.is_empty() {
+                write!(self.f, " xmlns=\"{}\"", escape_attr(uri))?;
+            } else {
+                write!(self.f, " xmlns:{prefix}=\"{}\"", escape_attr(uri))?;
+            }
+        }
+
+        for attr in &attrs {
+            let value = self.attr_value(&attr.value);
+            write!(
+                self.f,
+                " {}=\"{}\"",
+                qualified_name(attr.ns.as_deref(), &attr.name),
+                escape_attr(&value)
+            )?;
+        }
+
+        write!(self.f, ">")?;
+
+        for child in self.db.child_nodes(element.node_id)? {
+            match &child {
+                Node::Element(x) => self.element(x, &scope, &now_rendered, vec![])?,
+                node => self.leaf(node)?,
+            }
+        }
+
+        write!(self.f, "</{name}>")?;
+        Ok(())
+    }
+
+    fn leaf(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Text(x) => {
+                let value = normalize_line_endings(&x.value);
+                let value = if self.decode {
+                    Cow::Owned(decode_references(&value).into_owned())
+                } else {
+                    value
+                };
+                write!(self.f, "{}", escape_text(&value))?;
+            }
+            Node::CData(x) => {
+                write!(self.f, "{}", escape_text(&normalize_line_endings(&x.value)))?;
+            }
+            Node::Comment(x) if self.config.with_comments => {
+                write!(self.f, "<!--{}-->", normalize_line_endings(&x.value))?;
+            }
+            Node::ProcessingInstruction(x) => {
+                write!(self.f, "<?{}?>", normalize_line_endings(&x.value))?;
+            }
+            Node::Element(_) => unreachable!("elements are not leaves"),
+            Node::Comment(_) | Node::Declaration(_) | Node::Doctype(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Applies attribute-value normalization to a value as written in the
+    /// source: literal whitespace becomes spaces and references are decoded.
+    fn attr_value<'b>(&self, value: &'b str) -> Cow<'b, str> {
+        if !self.decode {
+            return Cow::Borrowed(value);
+        }
+
+        let value = normalize_line_endings(value);
+        let value = if value.contains(['\t', '\n']) {
+            Cow::Owned(value.replace(['\t', '\n'], " "))
+        } else {
+            value
+        };
+
+        match decode_references(&value) {
+            Cow::Borrowed(_) => value,
+            Cow::Owned(x) => Cow::Owned(x),
+        }
+    }
+}
+
+/// The declared prefix if the attribute is a namespace declaration, `""` for
+/// the default namespace.
+fn ns_decl_prefix(attr: &Attr) -> Option<&str> {
+    match (attr.ns.as_deref(), attr.name.as_str()) {
+        (Some("xmlns"), prefix) => Some(prefix),
+        (None, "xmlns") => Some(""),
+        _ => None,
+    }
+}
+
+fn normalize_line_endings(input: &str) -> Cow<'_, str> {
+    if input.contains('\r') {
+        Cow::Owned(input.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(input)
+    }
+}
+
+/// Replaces the predefined entities and character references. Any other
+/// entity reference is left as written.
+pub(crate) fn decode_references(input: &str) -> Cow<'_, str> {
+    if !input.contains('&') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut s = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('&') {
+        s.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            Some(x) => x,
+            None => break,
+        };
+
+        let decoded = match &rest[1..end] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            x if x.starts_with("#x") => u32::from_str_radix(&x[2..], 16)
+                .ok()
+                .and_then(char::from_u32),
+            x if x.starts_with('#') => x[1..].parse::<u32>().ok().and_then(char::from_u32),
+            _ => None,
+        };
+
+        match decoded {
+            Some(ch) => {
+                s.push(ch);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                s.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    s.push_str(rest);
+    Cow::Owned(s)
+}
+
+fn escape_text(input: &str) -> Cow<'_, str> {
+    if !input.contains(['&', '<', '>', '\r']) {
+        return Cow::Borrowed(input);
+    }
+
+    let mut s = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '\r' => s.push_str("&#xD;"),
+            ch => s.push(ch),
+        }
+    }
+    Cow::Owned(s)
+}
+
+fn escape_attr(input: &str) -> Cow<'_, str> {
+    if !input.contains(['&', '<', '"', '\t', '\n', '\r']) {
+        return Cow::Borrowed(input);
+    }
+
+    let mut s = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '"' => s.push_str("&quot;"),
+            '\t' => s.push_str("&#x9;"),
+            '\n' => s.push_str("&#xA;"),
+            '\r' => s.push_str("&#xD;"),
+            ch => s.push(ch),
+        }
+    }
+    Cow::Owned(s)
+}
@@ -0,0 +1,368 @@
+use std::{io::Write, sync::Arc};
+
+use arrow::{
+    array::{
+        ArrayRef, BinaryArray, BooleanArray, Date32Array, DurationMicrosecondArray, Float64Array,
+        Int64Array, StringArray, Time64MicrosecondArray, TimestampMicrosecondArray, UInt64Array,
+    },
+    compute::cast,
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    record_batch::RecordBatch,
+};
+use parquet::arrow::ArrowWriter;
+
+use crate::{
+    infer::{infer_type, Inferred},
+    model::Element,
+    table::{Cell, Column, ColumnExpr},
+    DocumentDb, Error, InferredType, QueryResult, Selector, Value,
+};
+
+const MICROS: i64 = 1_000_000;
+
+impl DocumentDb {
+    /// The `nodes` table, one row per node in document order.
+    pub fn nodes_to_arrow(&self) -> Result<RecordBatch, Error> {
+        self.query("SELECT * FROM nodes ORDER BY node_id", &[])?
+            .to_arrow()
+    }
+
+    /// The `attrs` table, one row per attribute in document order.
+    pub fn attrs_to_arrow(&self) -> Result<RecordBatch, Error> {
+        self.query("SELECT * FROM attrs ORDER BY attr_id", &[])?
+            .to_arrow()
+    }
+
+    /// One row per element matching `rows`, with a column for each of
+    /// `columns` typed by the inferred types of its values.
+    pub fn table_to_arrow(
+        &self,
+        rows: &Selector,
+        columns: &[Column],
+    ) -> Result<RecordBatch, Error> {
+        let cells = self.table_rows(rows, columns)?;
+        cells_to_arrow(vec![], columns, &cells)
+    }
+
+    /// Shreds the elements at an element path such as `/catalog/book` into a
+    /// table with their `node_id` and `parent_node_id`, a `@name` column for
+    /// each of their attributes and a column for each child element that
+    /// never has element children of its own.
+    pub fn path_to_arrow(&self, path: &str) -> Result<RecordBatch, Error> {
+        let statement = self.conn.prepare_cached(
+            r#"
+                SELECT nodes.node_id, nodes.parent_node_id, nodes.node_ns, nodes.node_name
+                FROM nodes, paths
+                WHERE paths.path = ?1 AND nodes.path_id = paths.path_id
+                ORDER BY nodes.node_id
+            "#,
+        )?;
+        let (parents, elements): (Vec<u64>, Vec<Element>) = statement
+            .query_map([path], |r| {
+                Ok((
+                    r.get::<_, u64>(1)?,
+                    Element {
+                        node_id: r.get(0)?,
+                        ns: r.get(2)?,
+                        name: r.get(3)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+
+        let statement = self.conn.prepare_cached(
+            r#"
+                SELECT attrs.attr_ns, attrs.attr_name FROM attrs, nodes, paths
+                WHERE paths.path = ?1 AND nodes.path_id = paths.path_id
+                    AND attrs.parent_node_id = nodes.node_id
+                GROUP BY attrs.attr_ns, attrs.attr_name
+                ORDER BY MIN(attrs.attr_id)
+            "#,
+        )?;
+        let mut columns = statement
+            .query_map([path], |r| {
+                let ns = r.get::<_, Option<String>>(0)?;
+                let name = r.get::<_, String>(1)?;
+                let column_name = match &ns {
+                    Some(ns) => format!("@{ns}:{name}"),
+                    None => format!("@{name}"),
+                };
+                Ok(Column::from_expr(column_name, ColumnExpr::Attr(ns, name)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let statement = self.conn.prepare_cached(
+            r#"
+                SELECT child.node_ns, child.node_name FROM nodes AS child, nodes AS parent, paths
+                WHERE paths.path = ?1 AND parent.path_id = paths.path_id
+                    AND child.parent_node_id = parent.node_id AND child.node_type = ?2
+                GROUP BY child.path_id
+                HAVING SUM(EXISTS (
+                    SELECT 1 FROM nodes AS grandchild
+                    WHERE grandchild.parent_node_id = child.node_id AND grandchild.node_type = ?2
+                )) = 0
+                ORDER BY MIN(child.node_id)
+            "#,
+        )?;
+        let children = statement
+            .query_map((path, crate::NodeType::Element as usize), |r| {
+                let ns = r.get::<_, Option<String>>(0)?;
+                let name = r.get::<_, String>(1)?;
+                let column_name = match &ns {
+                    Some(ns) => format!("{ns}:{name}"),
+                    None => name.clone(),
+                };
+                Ok(Column::from_expr(column_name, ColumnExpr::Child(ns, name)))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        columns.extend(children);
+
+        let cells = self.table_cells(&elements, &columns)?;
+        let node_ids = elements
+            .iter()
+            .map(|x| x.node_id as u64)
+            .collect::<Vec<_>>();
+        cells_to_arrow(
+            vec![
+                (
+                    Field::new("node_id", DataType::UInt64, false),
+                    Arc::new(UInt64Array::from(node_ids)) as ArrayRef,
+                ),
+                (
+                    Field::new("parent_node_id", DataType::UInt64, false),
+                    Arc::new(UInt64Array::from(parents)),
+                ),
+            ],
+            &columns,
+            &cells,
+        )
+    }
+}
+
+impl QueryResult {
+    /// Integer, real and blob columns keep their type when every value in
+    /// them has it, and anything else becomes text.
+    pub fn to_arrow(&self) -> Result<RecordBatch, Error> {
+        let mut fields = vec![];
+        let mut arrays = vec![];
+
+        for (i, name) in self.columns.iter().enumerate() {
+            let values = self.rows.iter().map(|row| &row[i]);
+
+            let mut data_type = None;
+            for value in values.clone() {
+                let ty = match value {
+                    Value::Null => continue,
+                    Value::Integer(_) => DataType::Int64,
+                    Value::Real(_) => DataType::Float64,
+                    Value::Text(_) => DataType::Utf8,
+                    Value::Blob(_) => DataType::Binary,
+                };
+                data_type = Some(match data_type {
+                    None => ty,
+                    Some(x) if x == ty => x,
+                    Some(DataType::Int64 | DataType::Float64)
+                        if matches!(ty, DataType::Int64 | DataType::Float64) =>
+                    {
+                        DataType::Float64
+                    }
+                    Some(_) => DataType::Utf8,
+                });
+            }
+
+            let array: ArrayRef = match data_type {
+                Some(DataType::Int64) => {
+                    Arc::new(values.map(Value::as_i64).collect::<Int64Array>())
+                }
+                Some(DataType::Float64) => {
+                    Arc::new(values.map(Value::as_f64).collect::<Float64Array>())
+                }
+                Some(DataType::Binary) => {
+                    Arc::new(values.map(Value::as_bytes).collect::<BinaryArray>())
+                }
+                _ => Arc::new(
+                    values
+                        .map(|x| match x {
+                            Value::Null => None,
+                            Value::Integer(x) => Some(x.to_string()),
+                            Value::Real(x) => Some(x.to_string()),
+                            Value::Text(x) => Some(x.clone()),
+                            Value::Blob(x) => Some(String::from_utf8_lossy(x).into_owned()),
+                        })
+                        .collect::<StringArray>(),
+                ),
+            };
+
+            fields.push(Field::new(name, array.data_type().clone(), true));
+            arrays.push(array);
+        }
+
+        Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+    }
+}
+
+/// Writes a record batch as a Parquet file. Parquet has no duration type,
+/// so duration columns are written as integer microseconds.
+pub fn write_parquet<W: Write + Send>(batch: &RecordBatch, f: W) -> Result<(), Error> {
+    let mut fields = vec![];
+    let mut arrays = vec![];
+    for (field, array) in batch.schema().fields().iter().zip(batch.columns()) {
+        if let DataType::Duration(_) = field.data_type() {
+            fields.push(Field::new(
+                field.name(),
+                DataType::Int64,
+                field.is_nullable(),
+            ));
+            arrays.push(cast(array, &DataType::Int64)?);
+        } else {
+            fields.push(field.as_ref().clone());
+            arrays.push(array.clone());
+        }
+    }
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?;
+
+    let mut writer = ArrowWriter::try_new(f, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+fn cells_to_arrow(
+    mut columns: Vec<(Field, ArrayRef)>,
+    table_columns: &[Column],
+    cells: &[Vec<Option<Cell>>],
+) -> Result<RecordBatch, Error> {
+    for (i, column) in table_columns.iter().enumerate() {
+        let cells = cells.iter().map(|row| row[i].as_ref()).collect::<Vec<_>>();
+        let array = typed_array(&cells);
+        columns.push((
+            Field::new(&column.name, array.data_type().clone(), true),
+            array,
+        ));
+    }
+
+    let (fields, arrays): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+/// The common type of the non-empty values of a column, falling back to
+/// strings when they disagree or a value doesn't fit the type.
+fn typed_array(cells: &[Option<&Cell>]) -> ArrayRef {
+    let mut types = vec![];
+    for cell in cells.iter().flatten() {
+        if !matches!(cell.ty, InferredType::Empty | InferredType::Whitespace)
+            && !types.contains(&cell.ty)
+        {
+            types.push(cell.ty);
+        }
+    }
+
+    let data_type = match types.as_slice() {
+        [InferredType::Int] => DataType::Int64,
+        [InferredType::Float]
+        | [InferredType::Int, InferredType::Float]
+        | [InferredType::Float, InferredType::Int] => DataType::Float64,
+        [InferredType::Boolean] => DataType::Boolean,
+        [InferredType::DateTime] => DataType::Timestamp(TimeUnit::Microsecond, None),
+        [InferredType::Date] => DataType::Date32,
+        [InferredType::Time] => DataType::Time64(TimeUnit::Microsecond),
+        [InferredType::Duration] => DataType::Duration(TimeUnit::Microsecond),
+        _ => DataType::Utf8,
+    };
+
+    build_array(cells, &data_type).unwrap_or_else(|| {
+        Arc::new(
+            cells
+                .iter()
+                .map(|x| x.map(|x| x.value.as_str()))
+                .collect::<StringArray>(),
+        )
+    })
+}
+
+fn build_array(cells: &[Option<&Cell>], data_type: &DataType) -> Option<ArrayRef> {
+    let values = cells.iter().map(|cell| {
+        cell.map(|x| x.value.trim())
+            .filter(|x| !x.is_empty())
+            .map(infer_type)
+    });
+
+    let array: ArrayRef = match data_type {
+        DataType::Int64 => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Int(x)) => i64::try_from(x).ok().map(Some),
+                    Some(_) => None,
+                })
+                .collect::<Option<Int64Array>>()?,
+        ),
+        DataType::Float64 => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Int(x)) => Some(Some(x as f64)),
+                    Some(Inferred::Float(x)) => Some(Some(x)),
+                    Some(_) => None,
+                })
+                .collect::<Option<Float64Array>>()?,
+        ),
+        DataType::Boolean => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Boolean(x)) => Some(Some(x)),
+                    Some(_) => None,
+                })
+                .collect::<Option<BooleanArray>>()?,
+        ),
+        // Timestamps with an offset are converted to UTC.
+        DataType::Timestamp(..) => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::DateTime(x)) => {
+                        Some(Some(x.timestamp_tz() * MICROS + x.time.microsecond as i64))
+                    }
+                    Some(_) => None,
+                })
+                .collect::<Option<TimestampMicrosecondArray>>()?,
+        ),
+        DataType::Date32 => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Date(x)) => i32::try_from(x.timestamp() / 86400).ok().map(Some),
+                    Some(_) => None,
+                })
+                .collect::<Option<Date32Array>>()?,
+        ),
+        DataType::Time64(_) => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Time(x)) => Some(Some(
+                        x.total_seconds() as i64 * MICROS + x.microsecond as i64,
+                    )),
+                    Some(_) => None,
+                })
+                .collect::<Option<Time64MicrosecondArray>>()?,
+        ),
+        DataType::Duration(_) => Arc::new(
+            values
+                .map(|x| match x {
+                    None => Some(None),
+                    Some(Inferred::Duration(x)) => Some(Some(
+                        x.signed_total_seconds() * MICROS + x.signed_microseconds() as i64,
+                    )),
+                    Some(_) => None,
+                })
+                .collect::<Option<DurationMicrosecondArray>>()?,
+        ),
+        _ => return None,
+    };
+
+    Some(array)
+}
@@ -0,0 +1,599 @@
+//! A `serde` deserializer that reads an element and its descendants.
+//!
+//! Struct fields are looked up in this order: `@name` is an attribute,
+//! `#text` is the element's own text, and any other name is the child
+//! elements with that name, or failing that an attribute. Repeated children
+//! deserialize into sequences, and elements with text only deserialize into
+//! primitives using the same inference as `infer_types`. Maps see attributes
+//! as `@name`, text as `#text` and children by name.
+
+use serde::de::{
+    self, value::StringDeserializer, DeserializeOwned, DeserializeSeed, IntoDeserializer,
+    MapAccess, SeqAccess, Unexpected, Visitor,
+};
+
+use crate::{
+    c14n::decode_references,
+    infer::{infer_type, Inferred},
+    model::{qualified_name, Attr, Element, Node},
+    DocumentDb, Error,
+};
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Deserialize(msg.to_string())
+    }
+}
+
+impl DocumentDb {
+    /// Deserializes the root element when `node_id` is 0, or the element at
+    /// `node_id`.
+    pub fn deserialize<T: DeserializeOwned>(&self, node_id: usize) -> Result<T, Error> {
+        T::deserialize(Deserializer::new(self, node_id)?)
+    }
+}
+
+pub struct Deserializer<'a> {
+    db: &'a DocumentDb,
+    element: Element,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(db: &'a DocumentDb, node_id: usize) -> Result<Self, Error> {
+        let element = db.element(if node_id == 0 { 1 } else { node_id })?;
+        Ok(Deserializer { db, element })
+    }
+
+    fn decoded(&self, value: String) -> String {
+        if self.db.options.raw_values() {
+            decode_references(&value).into_owned()
+        } else {
+            value
+        }
+    }
+
+    /// The element's own text and CDATA children, concatenated.
+    fn text(&self) -> Result<String, Error> {
+        let mut text = String::new();
+        for node in self.db.child_nodes(self.element.node_id)? {
+            match node {
+                Node::Text(x) => text.push_str(&self.decoded(x.value)),
+                Node::CData(x) => text.push_str(&x.value),
+                _ => {}
+            }
+        }
+        Ok(text)
+    }
+
+    fn value(&self) -> Result<ValueDeserializer, Error> {
+        Ok(ValueDeserializer(self.text()?))
+    }
+
+    fn attr_value(&self, attr: Attr) -> Entry {
+        Entry::Value(self.decoded(attr.value))
+    }
+
+    fn entries(self, fields: Option<&[&str]>) -> Result<ElementMap<'a>, Error> {
+        let attrs = self.db.attrs(self.element.node_id)?;
+        let children = self.db.children(self.element.node_id)?;
+        let mut entries = vec![];
+
+        match fields {
+            Some(fields) => {
+                for field in fields {
+                    let entry = if let Some(name) = field.strip_prefix('@') {
+                        find_attr(&attrs, name).map(|x| self.attr_value(x))
+                    } else if *field == "#text" {
+                        Some(Entry::Value(self.text()?))
+                    } else {
+                        let matching = children
+                            .iter()
+                            .filter(|x| qualified_name(x.ns.as_deref(), &x.name) == *field)
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        if !matching.is_empty() {
+                            Some(Entry::Children(matching))
+                        } else {
+                            find_attr(&attrs, field).map(|x| self.attr_value(x))
+                        }
+                    };
+
+                    if let Some(entry) = entry {
+                        entries.push((field.to_string(), entry));
+                    }
+                }
+            }
+            None => {
+                for attr in attrs {
+                    let key = format!("@{}", qualified_name(attr.ns.as_deref(), &attr.name));
+                    entries.push((key, self.attr_value(attr)));
+                }
+
+                let text = self.text()?;
+                if !text.trim().is_empty() {
+                    entries.push(("#text".to_string(), Entry::Value(text)));
+                }
+
+                let mut groups: Vec<(String, Vec<Element>)> = vec![];
+                for child in children {
+                    let name = qualified_name(child.ns.as_deref(), &child.name).into_owned();
+                    match groups.iter_mut().find(|(x, _)| *x == name) {
+                        Some((_, group)) => group.push(child),
+                        None => groups.push((name, vec![child])),
+                    }
+                }
+                entries.extend(groups.into_iter().map(|(k, v)| (k, Entry::Children(v))));
+            }
+        }
+
+        Ok(ElementMap {
+            db: self.db,
+            entries: entries.into_iter(),
+            value: None,
+        })
+    }
+}
+
+fn find_attr(attrs: &[Attr], name: &str) -> Option<Attr> {
+    attrs
+        .iter()
+        .find(|x| qualified_name(x.ns.as_deref(), &x.name) == name)
+        .cloned()
+}
+
+macro_rules! forward_to_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.value()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if !self.db.children(self.element.node_id)?.is_empty()
+            || !self.db.attrs(self.element.node_id)?.is_empty()
+        {
+            self.deserialize_map(visitor)
+        } else {
+            self.value()?.deserialize_any(visitor)
+        }
+    }
+
+    forward_to_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string deserialize_bytes
+        deserialize_byte_buf deserialize_identifier
+    }
+
+    /// An element without children, attributes or text is `None`.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let node_id = self.element.node_id;
+        if self.db.children(node_id)?.is_empty()
+            && self.db.attrs(node_id)?.is_empty()
+            && self.text()?.trim().is_empty()
+        {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// The child elements in document order.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let children = self.db.children(self.element.node_id)?;
+        visitor.visit_seq(ElementSeq {
+            db: self.db,
+            elements: children.into_iter(),
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(self.entries(None)?)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_map(self.entries(Some(fields))?)
+    }
+
+    /// Unit variants named by the element's text.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.value()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+enum Entry {
+    Value(String),
+    Children(Vec<Element>),
+}
+
+struct ElementMap<'a> {
+    db: &'a DocumentDb,
+    entries: std::vec::IntoIter<(String, Entry)>,
+    value: Option<Entry>,
+}
+
+impl<'de> MapAccess<'de> for ElementMap<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                let key: StringDeserializer<Error> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        match self.value.take() {
+            Some(Entry::Value(x)) => seed.deserialize(ValueDeserializer(x)),
+            Some(Entry::Children(elements)) => seed.deserialize(ChildrenDeserializer {
+                db: self.db,
+                elements,
+            }),
+            None => Err(de::Error::custom("value requested before key")),
+        }
+    }
+}
+
+struct ElementSeq<'a> {
+    db: &'a DocumentDb,
+    elements: std::vec::IntoIter<Element>,
+}
+
+impl<'de> SeqAccess<'de> for ElementSeq<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.elements.next() {
+            Some(element) => seed
+                .deserialize(Deserializer {
+                    db: self.db,
+                    element,
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Every child element with the same name. Sequences see all of them, and
+/// anything else the first.
+struct ChildrenDeserializer<'a> {
+    db: &'a DocumentDb,
+    elements: Vec<Element>,
+}
+
+impl<'a> ChildrenDeserializer<'a> {
+    fn first(self) -> Deserializer<'a> {
+        Deserializer {
+            db: self.db,
+            element: self.elements.into_iter().next().expect("no elements"),
+        }
+    }
+}
+
+macro_rules! forward_to_first {
+    ($($method:ident ( $($arg:ident : $ty:ty),* ))*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Error> {
+                self.first().$method($($arg,)* visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ChildrenDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.elements.len() > 1 {
+            self.deserialize_seq(visitor)
+        } else {
+            self.first().deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(ElementSeq {
+            db: self.db,
+            elements: self.elements.into_iter(),
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.elements.len() > 1 {
+            visitor.visit_some(self)
+        } else {
+            self.first().deserialize_option(visitor)
+        }
+    }
+
+    forward_to_first! {
+        deserialize_bool() deserialize_i8() deserialize_i16() deserialize_i32()
+        deserialize_i64() deserialize_u8() deserialize_u16() deserialize_u32()
+        deserialize_u64() deserialize_f32() deserialize_f64() deserialize_char()
+        deserialize_str() deserialize_string() deserialize_bytes() deserialize_byte_buf()
+        deserialize_unit() deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map() deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+        deserialize_identifier() deserialize_ignored_any()
+    }
+}
+
+/// An attribute value or the text of an element.
+struct ValueDeserializer(String);
+
+impl ValueDeserializer {
+    fn inferred(&self) -> Inferred {
+        infer_type(self.0.trim())
+    }
+
+    fn invalid<'de, V: Visitor<'de>>(&self, visitor: &V) -> Error {
+        de::Error::invalid_type(Unexpected::Str(&self.0), visitor)
+    }
+}
+
+macro_rules! deserialize_int {
+    ($($method:ident $parse:ty, $visit:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.inferred() {
+                    Inferred::Int(x) => visitor.visit_u64(x),
+                    _ => match self.0.trim().parse::<$parse>() {
+                        Ok(x) => visitor.$visit(x),
+                        Err(_) => Err(self.invalid(&visitor)),
+                    },
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.inferred() {
+            Inferred::Boolean(x) => visitor.visit_bool(x),
+            Inferred::Int(x) => visitor.visit_u64(x),
+            Inferred::Float(x) => match self.0.trim().parse::<i64>() {
+                Ok(x) => visitor.visit_i64(x),
+                Err(_) => visitor.visit_f64(x),
+            },
+            _ => visitor.visit_string(self.0),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.inferred() {
+            Inferred::Boolean(x) => visitor.visit_bool(x),
+            _ => match self.0.trim() {
+                "1" => visitor.visit_bool(true),
+                "0" => visitor.visit_bool(false),
+                _ => Err(self.invalid(&visitor)),
+            },
+        }
+    }
+
+    deserialize_int! {
+        deserialize_i8 i64, visit_i64
+        deserialize_i16 i64, visit_i64
+        deserialize_i32 i64, visit_i64
+        deserialize_i64 i64, visit_i64
+        deserialize_u8 u64, visit_u64
+        deserialize_u16 u64, visit_u64
+        deserialize_u32 u64, visit_u64
+        deserialize_u64 u64, visit_u64
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.inferred() {
+            Inferred::Int(x) => visitor.visit_f64(x as f64),
+            Inferred::Float(x) => visitor.visit_f64(x),
+            _ => Err(self.invalid(&visitor)),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_byte_buf(self.0.into_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_byte_buf(self.0.into_bytes())
+    }
+
+    /// Empty values are `None`.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Whitespace-separated items, as in an XML Schema list type.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let items = self
+            .0
+            .split_whitespace()
+            .map(|x| ValueDeserializer(x.to_string()))
+            .collect::<Vec<_>>();
+        visitor.visit_seq(ValueSeq(items.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        Err(self.invalid(&visitor))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        Err(self.invalid(&visitor))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let variant: StringDeserializer<Error> = self.0.trim().to_string().into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct ValueSeq(std::vec::IntoIter<ValueDeserializer>);
+
+impl<'de> SeqAccess<'de> for ValueSeq {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.0.next() {
+            Some(x) => seed.deserialize(x).map(Some),
+            None => Ok(None),
+        }
+    }
+}
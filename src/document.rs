@@ -1,15 +1,21 @@
 use std::{
     borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
     path::{Path, PathBuf},
 };
 
-use rusqlite::{types::ToSqlOutput, Batch, OpenFlags, OptionalExtension, Result, Row};
+use rusqlite::{
+    types::{FromSql, ToSqlOutput},
+    Batch, OpenFlags, OptionalExtension, Result, Row,
+};
 
 use crate::{
-    infer::InferredType,
+    builder, functions,
+    infer::{infer_type, InferredType},
     model,
-    writer::{Config, Print, State},
-    ParseOptions,
+    writer::{write_tree, Config, Print, State},
+    Error, ParseOptions,
 };
 
 #[derive(Debug)]
@@ -17,10 +23,14 @@ enum Mode {
     InMemory,
     OnDisk,
     TempDir(tempfile::TempDir),
+    Borrowed,
 }
 
 #[derive(Debug)]
 pub struct DocumentDb {
+    // Declared before `conn` so the functions' handle on the connection is
+    // dropped before it is closed.
+    _functions: Option<functions::Functions>,
     pub(crate) conn: rusqlite::Connection,
     pub(crate) options: ParseOptions,
     _mode: Mode,
@@ -35,6 +45,34 @@ PRAGMA temp_store = MEMORY;
 PRAGMA page_size = 65536;
 "#;
 
+pub(crate) const SCHEMA_VERSION: u32 = 3;
+
+const SQL_META: &str = r#"
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+"#;
+
+const SQL_ERRORS: &str = r#"
+CREATE TABLE parse_errors (
+    error_id INTEGER PRIMARY KEY,
+    parent_node_id INTEGER NOT NULL,
+    buffer_position INTEGER NOT NULL,
+    message TEXT NOT NULL,
+
+    FOREIGN KEY(parent_node_id) REFERENCES nodes(node_id)
+);
+"#;
+
+const SQL_PATHS: &str = r#"
+CREATE TABLE paths (
+    path_id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    depth INTEGER NOT NULL
+);
+"#;
+
 const SQL_SIMPLE: &str = r#"
 CREATE TABLE nodes (
     node_id INTEGER PRIMARY KEY,
@@ -47,6 +85,8 @@ CREATE TABLE nodes (
     node_value TEXT,
 
     buffer_position INTEGER NOT NULL,
+    path_id INTEGER,
+    depth INTEGER,
     FOREIGN KEY (parent_node_id) REFERENCES nodes(node_id)
 );
 
@@ -82,6 +122,8 @@ CREATE TABLE nodes (
 
     buffer_position INTEGER NOT NULL,
     inferred_type TEXT NOT NULL,
+    path_id INTEGER,
+    depth INTEGER,
     FOREIGN KEY (parent_node_id) REFERENCES nodes(node_id)
 );
 
@@ -105,6 +147,23 @@ VALUES
     (1, 0, 0, 1, NULL, NULL, NULL, 0, 'empty');
 "#;
 
+fn create_tables(conn: &rusqlite::Connection, infer_types: bool) -> Result<()> {
+    let mut batch = Batch::new(
+        conn,
+        if infer_types {
+            SQL_WITH_TYPES
+        } else {
+            SQL_SIMPLE
+        },
+    );
+
+    while let Some(mut stmt) = batch.next()? {
+        stmt.execute([])?;
+    }
+
+    Ok(())
+}
+
 impl DocumentDb {
     pub(crate) fn create_in_memory(options: ParseOptions) -> Result<Self> {
         let conn = rusqlite::Connection::open_in_memory()?;
@@ -126,41 +185,164 @@ impl DocumentDb {
         Self::_create(conn, Mode::OnDisk, options)
     }
 
+    /// Creates a temporary database holding only the `nodes` and `attrs`
+    /// tables, for a chunk of a parallel parse that is merged into the
+    /// document afterwards.
+    pub(crate) fn create_chunk(options: ParseOptions) -> Result<Self> {
+        let tmp = tempfile::tempdir()
+            .map_err(|_| rusqlite::Error::InvalidPath(PathBuf::from(":temp:")))?;
+        let conn = rusqlite::Connection::open(tmp.path().join("db"))?;
+        conn.execute_batch(PRAGMAS)?;
+        create_tables(&conn, options.infer_types)?;
+
+        Ok(Self {
+            _functions: None,
+            conn,
+            options,
+            _mode: Mode::TempDir(tmp),
+        })
+    }
+
     fn _create(conn: rusqlite::Connection, mode: Mode, options: ParseOptions) -> Result<Self> {
         conn.execute_batch(PRAGMAS)?;
+        create_tables(&conn, options.infer_types)?;
+        conn.execute_batch(SQL_ERRORS)?;
+        conn.execute_batch(SQL_PATHS)?;
+        conn.execute_batch(SQL_META)?;
+        write_meta(&conn, &options)?;
+        let functions = functions::register(&conn, &options)?;
 
-        let mut batch = Batch::new(
-            &conn,
-            if options.infer_types {
-                SQL_WITH_TYPES
-            } else {
-                SQL_SIMPLE
-            },
-        );
+        Ok(Self {
+            _functions: Some(functions),
+            conn,
+            options,
+            _mode: mode,
+        })
+    }
+
+    /// Opens a database previously created by this crate, restoring the
+    /// `ParseOptions` it was built with and migrating older schema versions.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut conn = rusqlite::Connection::open_with_flags(
+            path.as_ref(),
+            OpenFlags::SQLITE_OPEN_READ_WRITE,
+        )?;
 
-        while let Some(mut stmt) = batch.next()? {
-            stmt.execute([])?;
+        let (version, options) = match read_meta(&conn)? {
+            Some(meta) => meta,
+            None => return Err(Error::Unversioned),
+        };
+
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version, SCHEMA_VERSION));
         }
+        check_schema(&conn, &options)?;
+
+        migrate(&mut conn, version, &options)?;
+        let functions = functions::register(&conn, &options)?;
 
         Ok(Self {
+            _functions: Some(functions),
             conn,
             options,
-            _mode: mode,
+            _mode: Mode::OnDisk,
         })
     }
 
-    pub fn open<P: AsRef<Path>>(path: P, options: ParseOptions) -> Result<Self> {
+    /// Opens a finished database read-only as an immutable URI, which skips
+    /// SQLite's locking so any number of threads or processes may read it.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let conn = rusqlite::Connection::open_with_flags(
+            immutable_uri(path.as_ref()),
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        let (version, options) = match read_meta(&conn)? {
+            Some(meta) => meta,
+            None => return Err(Error::Unversioned),
+        };
+
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version, SCHEMA_VERSION));
+        }
+        check_schema(&conn, &options)?;
+
+        if version < SCHEMA_VERSION {
+            return Err(Error::MigrationRequired(version));
+        }
+        let functions = functions::register(&conn, &options)?;
+
+        Ok(Self {
+            _functions: Some(functions),
+            conn,
+            options,
+            _mode: Mode::OnDisk,
+        })
+    }
+
+    /// Opens a database created before the `meta` table existed. The given
+    /// options are checked against the schema where possible and then recorded.
+    pub fn open_unversioned<P: AsRef<Path>>(path: P, options: ParseOptions) -> Result<Self, Error> {
+        let mut conn = rusqlite::Connection::open_with_flags(
             path.as_ref(),
             OpenFlags::SQLITE_OPEN_READ_WRITE,
         )?;
+
+        if read_meta(&conn)?.is_some() {
+            drop(conn);
+            return Self::open(path);
+        }
+
+        check_schema(&conn, &options)?;
+
+        migrate(&mut conn, 0, &options)?;
+        let functions = functions::register(&conn, &options)?;
+
         Ok(Self {
+            _functions: Some(functions),
             conn,
             options,
             _mode: Mode::OnDisk,
         })
     }
 
+    /// Wraps a connection owned elsewhere, such as the one a custom SQL
+    /// function is called on, without closing it when dropped.
+    pub(crate) unsafe fn borrowed(
+        handle: *mut rusqlite::ffi::sqlite3,
+        options: ParseOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            _functions: None,
+            conn: rusqlite::Connection::from_handle(handle)?,
+            options,
+            _mode: Mode::Borrowed,
+        })
+    }
+
+    pub(crate) fn into_temp_dir(self) -> Option<tempfile::TempDir> {
+        match self._mode {
+            Mode::TempDir(dir) => Some(dir),
+            _ => None,
+        }
+    }
+
+    pub fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    pub fn schema_version(&self) -> Result<u32> {
+        self.conn.query_row(
+            r#"
+                SELECT value FROM meta WHERE key = 'schema_version'
+            "#,
+            [],
+            |r| r.get::<_, u32>(0),
+        )
+    }
+
     pub fn element_count(&self) -> Result<usize> {
         let count = self.conn.query_row(
             r#"
@@ -197,9 +379,12 @@ impl DocumentDb {
     pub fn parent_element_id(&self, node_id: usize) -> Result<usize> {
         let node_id = self.conn.query_row(
             r#"
-                SELECT parent_node_id FROM nodes WHERE node_id = ?1
+                SELECT parent.node_id FROM nodes, nodes AS parent
+                    WHERE nodes.node_id = ?1
+                    AND parent.node_id = nodes.parent_node_id
+                    AND parent.node_type = ?2
             "#,
-            [node_id],
+            [node_id, NodeType::Element as usize],
             |r| r.get::<_, usize>(0),
         )?;
         Ok(node_id)
@@ -208,13 +393,14 @@ impl DocumentDb {
     pub fn prev_sibling_element_id(&self, node_id: usize) -> Result<usize> {
         let node_id = self.conn.query_row(
             r#"
-                SELECT parent_node_id FROM nodes WHERE parent_node_id = (SELECT parent_node_id
+                SELECT node_id FROM nodes WHERE parent_node_id = (SELECT parent_node_id
                     FROM nodes
                     WHERE node_id = ?1)
                 AND node_order < (SELECT node_order FROM nodes WHERE node_id = ?1)
+                AND node_type = ?2
                 ORDER BY node_order DESC LIMIT 1;
             "#,
-            [node_id],
+            [node_id, NodeType::Element as usize],
             |r| r.get::<_, usize>(0),
         )?;
         Ok(node_id)
@@ -223,13 +409,14 @@ impl DocumentDb {
     pub fn next_sibling_element_id(&self, node_id: usize) -> Result<usize> {
         let node_id = self.conn.query_row(
             r#"
-                SELECT parent_node_id FROM nodes WHERE parent_node_id = (SELECT parent_node_id
+                SELECT node_id FROM nodes WHERE parent_node_id = (SELECT parent_node_id
                     FROM nodes
                     WHERE node_id = ?1)
                 AND node_order > (SELECT node_order FROM nodes WHERE node_id = ?1)
+                AND node_type = ?2
                 ORDER BY node_order ASC LIMIT 1;
             "#,
-            [node_id],
+            [node_id, NodeType::Element as usize],
             |r| r.get::<_, usize>(0),
         )?;
         Ok(node_id)
@@ -472,6 +659,102 @@ impl DocumentDb {
         Ok(count > 0)
     }
 
+    /// Whether the node has no child elements and no non-empty text, which is
+    /// what `:empty` matches. Comments and processing instructions are ignored.
+    pub fn is_empty_element(&self, node_id: usize) -> Result<bool> {
+        let count = self.conn.query_row(
+            r#"
+                SELECT COUNT(*) FROM nodes
+                    WHERE parent_node_id = ?1 AND node_id != ?1 AND (
+                        node_type = ?2 OR (node_type IN (?3, ?4) AND node_value != '')
+                    )
+                    LIMIT 1
+            "#,
+            [
+                node_id,
+                NodeType::Element as usize,
+                NodeType::Text as usize,
+                NodeType::CData as usize,
+            ],
+            |r| r.get::<_, usize>(0),
+        )?;
+
+        Ok(count == 0)
+    }
+
+    /// The concatenated text and CDATA of all descendents in document order.
+    pub fn text_content(&self, node_id: usize) -> Result<String> {
+        let statement = self.conn.prepare_cached(
+            r#"
+            WITH RECURSIVE descendents(node_id, node_type, node_value, sort_key) AS (
+                SELECT node_id, node_type, node_value, printf('%010d', node_order) FROM nodes
+                    WHERE (parent_node_id = ?1 AND node_id != ?1)
+                    OR (node_id = ?1 AND node_type IN (?2, ?3))
+                UNION ALL
+                SELECT nodes.node_id, nodes.node_type, nodes.node_value,
+                    descendents.sort_key || printf('%010d', nodes.node_order)
+                    FROM nodes, descendents
+                    WHERE nodes.parent_node_id = descendents.node_id
+            )
+            SELECT node_value FROM descendents
+                WHERE node_type IN (?2, ?3)
+                ORDER BY sort_key
+        "#,
+        )?;
+
+        let values = statement
+            .query_map(
+                [node_id, NodeType::Text as usize, NodeType::CData as usize],
+                |r| r.get::<_, Option<String>>(0),
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(values.into_iter().flatten().collect())
+    }
+
+    /// Whether any text or CDATA child of the node has the given inferred
+    /// type. Without stored types, the text children are inferred on the fly.
+    pub fn has_text_of_type(&self, node_id: usize, ty: InferredType) -> Result<bool> {
+        if !self.options.infer_types {
+            let statement = self.conn.prepare_cached(
+                r#"
+                SELECT node_value FROM nodes
+                    WHERE parent_node_id = ?1 AND node_id != ?1 AND node_type IN (?2, ?3)
+            "#,
+            )?;
+
+            let values = statement
+                .query_map(
+                    [node_id, NodeType::Text as usize, NodeType::CData as usize],
+                    |r| r.get::<_, Option<String>>(0),
+                )?
+                .collect::<Result<Vec<_>>>()?;
+
+            return Ok(values
+                .into_iter()
+                .flatten()
+                .any(|x| infer_type(&x).as_type() == ty));
+        }
+
+        let count = self.conn.query_row(
+            r#"
+                SELECT COUNT(*) FROM nodes
+                    WHERE parent_node_id = ?1 AND node_id != ?1
+                        AND node_type IN (?2, ?3) AND inferred_type = ?4
+                    LIMIT 1
+            "#,
+            (
+                node_id,
+                NodeType::Text as usize,
+                NodeType::CData as usize,
+                ty.as_str(),
+            ),
+            |r| r.get::<_, usize>(0),
+        )?;
+
+        Ok(count > 0)
+    }
+
     pub fn document_child_nodes(&self) -> Result<Vec<model::Node>> {
         self.child_nodes(0)
     }
@@ -579,6 +862,26 @@ impl DocumentDb {
         Ok(result.parse().unwrap())
     }
 
+    pub fn parse_errors(&self) -> Result<Vec<model::ParseError>> {
+        let statement = self.conn.prepare_cached(
+            r#"
+                SELECT error_id, parent_node_id, buffer_position, message FROM parse_errors
+                ORDER BY error_id
+            "#,
+        )?;
+
+        statement
+            .query_map([], |r| {
+                Ok(model::ParseError {
+                    error_id: r.get::<_, usize>(0)?,
+                    parent_node_id: r.get::<_, usize>(1)?,
+                    buffer_position: r.get::<_, u64>(2)?,
+                    message: r.get::<_, String>(3)?,
+                })
+            })?
+            .collect()
+    }
+
     pub fn elements_matching_attr_value(
         &self,
         attr_name: &str,
@@ -608,6 +911,216 @@ impl DocumentDb {
             .collect()
     }
 
+    /// Every distinct element path with its counts and text types, ordered by
+    /// path.
+    pub fn path_summary(&self) -> Result<Vec<model::PathSummary>> {
+        let statement = self.conn.prepare_cached(
+            r#"
+            WITH per_parent AS (
+                SELECT child.path_id, parent.path_id AS parent_path_id, COUNT(*) AS count
+                    FROM nodes AS child, nodes AS parent
+                    WHERE child.node_type = ?1 AND child.path_id IS NOT NULL
+                    AND parent.node_id = child.parent_node_id
+                    GROUP BY child.path_id, child.parent_node_id
+            ),
+            per_path AS (
+                SELECT path_id, COUNT(*) AS count FROM nodes
+                    WHERE node_type = ?1 AND path_id IS NOT NULL
+                    GROUP BY path_id
+            )
+            SELECT paths.path_id, paths.path, paths.depth, SUM(per_parent.count),
+                CASE WHEN COUNT(*) < COALESCE(MAX(per_path.count), 1)
+                    THEN 0 ELSE MIN(per_parent.count) END,
+                MAX(per_parent.count)
+            FROM paths
+                JOIN per_parent ON per_parent.path_id = paths.path_id
+                LEFT JOIN per_path ON per_path.path_id = per_parent.parent_path_id
+            GROUP BY paths.path_id
+            ORDER BY paths.path
+        "#,
+        )?;
+
+        let mut summary = statement
+            .query_map([NodeType::Element as usize], |r| {
+                Ok(model::PathSummary {
+                    path_id: r.get(0)?,
+                    path: r.get(1)?,
+                    depth: r.get(2)?,
+                    count: r.get(3)?,
+                    min_per_parent: r.get(4)?,
+                    max_per_parent: r.get(5)?,
+                    types: vec![],
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut types = self.element_text_types::<usize>("element.path_id")?;
+        for x in summary.iter_mut() {
+            x.types = types.remove(&x.path_id).unwrap_or_default();
+        }
+
+        Ok(summary)
+    }
+
+    pub fn stats(&self) -> Result<model::Stats> {
+        let statement = self.conn.prepare_cached(
+            r#"
+            SELECT node_type, COUNT(*) FROM nodes WHERE node_id != 0
+                GROUP BY node_type ORDER BY node_type
+        "#,
+        )?;
+        let node_counts = statement
+            .query_map([], |r| {
+                Ok((
+                    NodeType::try_from(r.get::<_, u8>(0)?).unwrap(),
+                    r.get::<_, usize>(1)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let statement = self.conn.prepare_cached(&format!(
+            r#"
+            SELECT {} AS name, COUNT(*) AS count FROM nodes AS element
+                WHERE node_type = ?1
+                GROUP BY name ORDER BY count DESC, name
+        "#,
+            sql_qualified_name("element.node_ns", "element.node_name")
+        ))?;
+        let element_names = statement
+            .query_map([NodeType::Element as usize], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let statement = self.conn.prepare_cached(&format!(
+            r#"
+            SELECT {} AS name, COUNT(*) AS count FROM attrs
+                GROUP BY name ORDER BY count DESC, name
+        "#,
+            sql_qualified_name("attr_ns", "attr_name")
+        ))?;
+        let attr_names = statement
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let statement = self.conn.prepare_cached(
+            r#"
+            SELECT depth, COUNT(*) FROM nodes
+                WHERE node_type = ?1 AND depth IS NOT NULL
+                GROUP BY depth ORDER BY depth
+        "#,
+        )?;
+        let depths = statement
+            .query_map([NodeType::Element as usize], |r| {
+                Ok((r.get::<_, usize>(0)?, r.get::<_, usize>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let max_fan_out = self.conn.query_row(
+            r#"
+            SELECT COALESCE(MAX(count), 0) FROM (
+                SELECT COUNT(*) AS count FROM nodes
+                    WHERE node_type = ?1 AND node_id != parent_node_id
+                    GROUP BY parent_node_id
+            )
+        "#,
+            [NodeType::Element as usize],
+            |r| r.get::<_, usize>(0),
+        )?;
+
+        let text_bytes = self.conn.query_row(
+            r#"
+            SELECT COALESCE(SUM(LENGTH(CAST(node_value AS BLOB))), 0) FROM nodes
+                WHERE node_type IN (?1, ?2)
+        "#,
+            [NodeType::Text as usize, NodeType::CData as usize],
+            |r| r.get::<_, usize>(0),
+        )?;
+
+        let element_types = self
+            .element_text_types::<String>(&sql_qualified_name(
+                "element.node_ns",
+                "element.node_name",
+            ))?
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .collect();
+
+        let statement = self.conn.prepare_cached(&format!(
+            "SELECT {}, {} FROM attrs",
+            sql_qualified_name("attr_ns", "attr_name"),
+            if self.options.infer_types {
+                "inferred_type"
+            } else {
+                "attr_value"
+            }
+        ))?;
+        let mut rows = statement.query([])?;
+        let mut attr_types = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            let value = row.get::<_, String>(1)?;
+            let ty = if self.options.infer_types {
+                value.parse().unwrap_or(InferredType::Empty)
+            } else {
+                infer_type(&value).as_type()
+            };
+            add_count(attr_types.entry(row.get::<_, String>(0)?).or_default(), ty);
+        }
+
+        Ok(model::Stats {
+            node_counts,
+            element_names,
+            attr_names,
+            depths,
+            max_fan_out,
+            text_bytes,
+            element_types,
+            attr_types: attr_types
+                .into_iter()
+                .map(|(k, v)| (k, sorted_counts(v)))
+                .collect(),
+        })
+    }
+
+    /// The type inferred from the direct text of each element, counted per
+    /// `key` and most common first. Elements without text count as empty.
+    fn element_text_types<K>(&self, key: &str) -> Result<HashMap<K, Vec<(InferredType, usize)>>>
+    where
+        K: FromSql + Eq + Hash,
+    {
+        let statement = self.conn.prepare_cached(&format!(
+            r#"
+            SELECT {key}, (
+                SELECT group_concat(text.node_value, '') FROM nodes AS text
+                    WHERE text.parent_node_id = element.node_id
+                    AND text.node_id != text.parent_node_id
+                    AND text.node_type IN (?2, ?3)
+            )
+            FROM nodes AS element
+            WHERE element.node_type = ?1 AND element.path_id IS NOT NULL
+        "#
+        ))?;
+
+        let mut rows = statement.query([
+            NodeType::Element as usize,
+            NodeType::Text as usize,
+            NodeType::CData as usize,
+        ])?;
+
+        let mut types = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let ty = match row.get::<_, Option<String>>(1)? {
+                Some(x) => infer_type(&x).as_type(),
+                None => InferredType::Empty,
+            };
+            add_count(types.entry(row.get::<_, K>(0)?).or_default(), ty);
+        }
+
+        Ok(types
+            .into_iter()
+            .map(|(k, v)| (k, sorted_counts(v)))
+            .collect())
+    }
+
     #[inline]
     pub fn to_string_pretty(&self) -> String {
         let mut s = vec![];
@@ -622,6 +1135,330 @@ impl DocumentDb {
         self.print(&mut s, config, &State::new(self, true)).unwrap();
         String::from_utf8(s).expect("invalid UTF-8")
     }
+
+    pub fn subtree_to_string(&self, node_id: usize, config: &Config) -> Result<String> {
+        let mut s = vec![];
+        match self.write_subtree(node_id, &mut s, config) {
+            Ok(()) => {}
+            Err(Error::Db(e)) => return Err(e),
+            Err(e) => panic!("{e}"),
+        }
+        Ok(String::from_utf8(s).expect("invalid UTF-8"))
+    }
+
+    /// Writes the node and everything below it. An element is given the
+    /// namespace declarations in scope from its ancestors, so the fragment
+    /// can be parsed on its own.
+    pub fn write_subtree<W: std::io::Write>(
+        &self,
+        node_id: usize,
+        mut f: W,
+        config: &Config,
+    ) -> Result<(), Error> {
+        if node_id == 0 {
+            return write_tree(&mut f, config, &State::new(self, config.is_pretty), 0);
+        }
+
+        let ns_decls = match self.node(node_id)? {
+            model::Node::Element(_) => self.in_scope_ns_decls(node_id)?,
+            _ => vec![],
+        };
+
+        let context = State {
+            ns_decls: &ns_decls,
+            ..State::new(self, config.is_pretty)
+        };
+
+        write_tree(&mut f, config, &context, node_id)
+    }
+
+    /// The `xmlns` attributes of the ancestors of an element that are not
+    /// overridden along the way or on the element itself, outermost first.
+    fn in_scope_ns_decls(&self, node_id: usize) -> Result<Vec<model::Attr>> {
+        let statement = self.conn.prepare_cached(
+            r#"
+            WITH RECURSIVE ancestors(node_id, level) AS (
+                SELECT node_id, 0 FROM nodes WHERE node_id = ?1
+                UNION ALL
+                SELECT nodes.parent_node_id, ancestors.level + 1
+                    FROM nodes, ancestors
+                    WHERE nodes.node_id = ancestors.node_id
+                    AND nodes.parent_node_id != nodes.node_id
+            )
+            SELECT attrs.attr_id, attrs.attr_ns, attrs.attr_name, attrs.attr_value, ancestors.level
+                FROM ancestors, attrs
+                WHERE attrs.parent_node_id = ancestors.node_id
+                AND (attrs.attr_ns = 'xmlns' OR (attrs.attr_ns IS NULL AND attrs.attr_name = 'xmlns'))
+                ORDER BY ancestors.level, attrs.attr_order
+        "#,
+        )?;
+
+        let values = statement
+            .query_map([node_id], |r| {
+                Ok((
+                    model::Attr {
+                        attr_id: r.get(0)?,
+                        ns: r.get(1)?,
+                        name: r.get(2)?,
+                        value: r.get(3)?,
+                    },
+                    r.get::<_, usize>(4)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        // The nearest declaration of each prefix wins.
+        let mut decls: Vec<(model::Attr, usize)> = vec![];
+        for (attr, level) in values {
+            let prefix = |x: &model::Attr| x.ns.as_ref().map(|_| x.name.clone());
+            if !decls.iter().any(|(x, _)| prefix(x) == prefix(&attr)) {
+                decls.push((attr, level));
+            }
+        }
+
+        // Declarations on the element are printed with its own attributes,
+        // and an undeclared default namespace needs no `xmlns=""`.
+        Ok(decls
+            .into_iter()
+            .rev()
+            .filter(|(x, level)| *level > 0 && !(x.ns.is_none() && x.value.is_empty()))
+            .map(|(x, _)| x)
+            .collect())
+    }
+
+    /// An XPath-like path such as `/a/b[3]` to the node. Siblings are only
+    /// indexed when more than one shares the name.
+    pub fn path(&self, node_id: usize) -> Result<String> {
+        let statement = self.conn.prepare_cached(
+            r#"
+            SELECT nodes.parent_node_id, nodes.node_type, nodes.node_ns, nodes.node_name,
+                (SELECT COUNT(*) FROM nodes AS sibling
+                    WHERE sibling.parent_node_id = nodes.parent_node_id
+                    AND sibling.node_id != sibling.parent_node_id
+                    AND sibling.node_type = nodes.node_type
+                    AND sibling.node_name IS nodes.node_name
+                    AND sibling.node_order < nodes.node_order),
+                (SELECT COUNT(*) FROM nodes AS sibling
+                    WHERE sibling.parent_node_id = nodes.parent_node_id
+                    AND sibling.node_id != sibling.parent_node_id
+                    AND sibling.node_type = nodes.node_type
+                    AND sibling.node_name IS nodes.node_name)
+            FROM nodes WHERE node_id = ?1
+        "#,
+        )?;
+
+        let mut steps = vec![];
+        let mut current = node_id;
+
+        while current != 0 {
+            let (parent, node_type, ns, name, index, count) =
+                statement.query_row([current], |r| {
+                    Ok((
+                        r.get::<_, usize>(0)?,
+                        NodeType::try_from(r.get::<_, u8>(1)?).unwrap(),
+                        r.get::<_, Option<String>>(2)?,
+                        r.get::<_, Option<String>>(3)?,
+                        r.get::<_, usize>(4)?,
+                        r.get::<_, usize>(5)?,
+                    ))
+                })?;
+
+            let step = match (node_type, ns, name) {
+                (NodeType::Element, Some(ns), Some(name)) => format!("{ns}:{name}"),
+                (NodeType::Element, None, Some(name)) => name,
+                (NodeType::Text, _, _) | (NodeType::CData, _, _) => "text()".to_string(),
+                (NodeType::Comment, _, _) => "comment()".to_string(),
+                (NodeType::ProcessingInstruction, _, _) => "processing-instruction()".to_string(),
+                _ => "node()".to_string(),
+            };
+
+            if count > 1 {
+                steps.push(format!("{step}[{}]", index + 1));
+            } else {
+                steps.push(step);
+            }
+
+            current = parent;
+        }
+
+        if steps.is_empty() {
+            return Ok("/".to_string());
+        }
+
+        let mut path = String::new();
+        for step in steps.iter().rev() {
+            path.push('/');
+            path.push_str(step);
+        }
+
+        Ok(path)
+    }
+}
+
+fn sql_qualified_name(ns: &str, name: &str) -> String {
+    format!("CASE WHEN {ns} IS NULL THEN {name} ELSE {ns} || ':' || {name} END")
+}
+
+fn add_count(counts: &mut Vec<(InferredType, usize)>, ty: InferredType) {
+    match counts.iter_mut().find(|(x, _)| *x == ty) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((ty, 1)),
+    }
+}
+
+fn sorted_counts(mut counts: Vec<(InferredType, usize)>) -> Vec<(InferredType, usize)> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1));
+    counts
+}
+
+fn write_meta(conn: &rusqlite::Connection, options: &ParseOptions) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+            INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)
+        "#,
+    )?;
+
+    stmt.execute(("schema_version", SCHEMA_VERSION.to_string()))?;
+    stmt.execute(("crate_version", env!("CARGO_PKG_VERSION")))?;
+    stmt.execute(("ignore_whitespace", options.ignore_whitespace.to_string()))?;
+    stmt.execute(("infer_types", options.infer_types.to_string()))?;
+    stmt.execute(("case_insensitive", options.case_insensitive.to_string()))?;
+    stmt.execute(("recover", options.recover.to_string()))?;
+    stmt.execute(("html", options.html.to_string()))?;
+    stmt.execute((
+        "json",
+        options.json.map_or("false", |x| x.as_str()).to_string(),
+    ))?;
+
+    Ok(())
+}
+
+fn read_meta(conn: &rusqlite::Connection) -> Result<Option<(u32, ParseOptions)>, Error> {
+    let has_meta = conn.query_row(
+        r#"
+            SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'meta'
+        "#,
+        [],
+        |r| r.get::<_, usize>(0),
+    )? > 0;
+
+    if !has_meta {
+        return Ok(None);
+    }
+
+    let get_optional = |key: &'static str| -> Result<Option<String>> {
+        conn.query_row(
+            r#"
+                SELECT value FROM meta WHERE key = ?1
+            "#,
+            [key],
+            |r| r.get::<_, String>(0),
+        )
+        .optional()
+    };
+    let get = |key: &'static str| -> Result<String, Error> {
+        get_optional(key)?.ok_or(Error::InvalidMeta(key))
+    };
+    let get_bool = |key: &'static str| -> Result<bool, Error> {
+        get(key)?
+            .parse::<bool>()
+            .map_err(|_| Error::InvalidMeta(key))
+    };
+    let get_optional_bool = |key: &'static str| -> Result<bool, Error> {
+        match get_optional(key)? {
+            Some(x) => x.parse::<bool>().map_err(|_| Error::InvalidMeta(key)),
+            None => Ok(false),
+        }
+    };
+
+    let version = get("schema_version")?
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidMeta("schema_version"))?;
+
+    let options = ParseOptions {
+        ignore_whitespace: get_bool("ignore_whitespace")?,
+        infer_types: get_bool("infer_types")?,
+        case_insensitive: get_bool("case_insensitive")?,
+        // Added in schema version 2.
+        recover: get_optional_bool("recover")?,
+        html: get_optional_bool("html")?,
+        json: match get_optional("json")?.as_deref() {
+            None | Some("false") => None,
+            Some(x) => Some(x.parse().map_err(|_| Error::InvalidMeta("json"))?),
+        },
+        ..Default::default()
+    };
+
+    Ok(Some((version, options)))
+}
+
+fn immutable_uri(path: &Path) -> String {
+    let mut uri = String::from("file:");
+    for ch in path.to_string_lossy().chars() {
+        match ch {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3F"),
+            '#' => uri.push_str("%23"),
+            ch => uri.push(ch),
+        }
+    }
+    uri.push_str("?immutable=1");
+    uri
+}
+
+/// Checks the options that shape the schema against the actual tables, so a
+/// meta table that disagrees with them is caught when opening.
+fn check_schema(conn: &rusqlite::Connection, options: &ParseOptions) -> Result<(), Error> {
+    if has_column(conn, "nodes", "inferred_type")? != options.infer_types {
+        return Err(Error::IncompatibleOptions("infer_types"));
+    }
+
+    Ok(())
+}
+
+fn has_column(conn: &rusqlite::Connection, table: &str, column: &str) -> Result<bool> {
+    let count = conn.query_row(
+        r#"
+            SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2
+        "#,
+        [table, column],
+        |r| r.get::<_, usize>(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+fn migrate(conn: &mut rusqlite::Connection, from: u32, options: &ParseOptions) -> Result<()> {
+    if from == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+
+    for version in from..SCHEMA_VERSION {
+        match version {
+            0 => {
+                tx.execute_batch(SQL_META)?;
+            }
+            1 => {
+                tx.execute_batch(SQL_ERRORS)?;
+            }
+            2 => {
+                tx.execute_batch(SQL_PATHS)?;
+                tx.execute_batch(
+                    r#"
+                    ALTER TABLE nodes ADD COLUMN path_id INTEGER;
+                    ALTER TABLE nodes ADD COLUMN depth INTEGER;
+                "#,
+                )?;
+                builder::add_paths(&tx)?;
+            }
+            _ => unreachable!("no migration from schema version {version}"),
+        }
+    }
+
+    write_meta(&tx, options)?;
+    tx.commit()
 }
 
 #[derive(Debug, Clone, Copy)]
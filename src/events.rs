@@ -0,0 +1,107 @@
+use crate::{
+    model::{Event, Node},
+    DocumentDb,
+};
+
+/// Iterator over the events of a document or subtree, in document order.
+///
+/// The children of an element are loaded when it is entered and held until
+/// it ends, so memory use grows with the children of the open elements. A
+/// deep tree is cheap, but an element with millions of children holds all of
+/// them at once.
+pub struct Events<'a> {
+    doc: &'a DocumentDb,
+    stack: Vec<Frame>,
+    start_document: bool,
+}
+
+struct Frame {
+    end: Option<Event>,
+    children: std::vec::IntoIter<Node>,
+}
+
+impl DocumentDb {
+    /// The events of `node_id` and its descendants. Node 0 yields the whole
+    /// document between `StartDocument` and `EndDocument`.
+    pub fn events(&self, node_id: usize) -> rusqlite::Result<Events<'_>> {
+        let frame = if node_id == 0 {
+            Frame {
+                end: Some(Event::EndDocument),
+                children: self.child_nodes(0)?.into_iter(),
+            }
+        } else {
+            Frame {
+                end: None,
+                children: vec![self.node(node_id)?].into_iter(),
+            }
+        };
+
+        Ok(Events {
+            doc: self,
+            stack: vec![frame],
+            start_document: node_id == 0,
+        })
+    }
+}
+
+impl Events<'_> {
+    fn next_event(&mut self) -> rusqlite::Result<Option<Event>> {
+        if self.start_document {
+            self.start_document = false;
+            return Ok(Some(Event::StartDocument));
+        }
+
+        loop {
+            let frame = match self.stack.last_mut() {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            let node = match frame.children.next() {
+                Some(x) => x,
+                None => {
+                    let end = self.stack.pop().and_then(|x| x.end);
+                    if end.is_some() {
+                        return Ok(end);
+                    }
+                    continue;
+                }
+            };
+
+            let event = match node {
+                Node::Element(element) => {
+                    let attrs = self.doc.attrs(element.node_id)?;
+                    let children = self.doc.child_nodes(element.node_id)?;
+                    self.stack.push(Frame {
+                        end: Some(Event::EndElement(element.clone())),
+                        children: children.into_iter(),
+                    });
+                    Event::StartElement { element, attrs }
+                }
+                Node::Text(x) => Event::Text(x),
+                Node::CData(x) => Event::CData(x),
+                Node::Comment(x) => Event::Comment(x),
+                Node::ProcessingInstruction(x) => Event::ProcessingInstruction(x),
+                Node::Declaration(x) => Event::Declaration(x),
+                Node::Doctype(x) => Event::Doctype(x),
+            };
+
+            return Ok(Some(event));
+        }
+    }
+}
+
+impl Iterator for Events<'_> {
+    type Item = rusqlite::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_event() {
+            Ok(x) => x.map(Ok),
+            Err(e) => {
+                // Nothing sensible can follow a failed query.
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
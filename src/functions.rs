@@ -0,0 +1,264 @@
+use std::{
+    marker::PhantomData,
+    os::raw::c_int,
+    sync::{Arc, Mutex, TryLockError},
+};
+
+use rusqlite::{
+    ffi,
+    functions::FunctionFlags,
+    vtab::{
+        self, eponymous_only_module, IndexConstraintOp, IndexInfo, VTab, VTabConfig,
+        VTabConnection, VTabCursor, Values,
+    },
+    Connection, Error, OptionalExtension, Result,
+};
+
+use crate::{document::DocumentDb, model, writer::Config, ParseOptions, Selector};
+
+const SELECT_COLUMN_ROOT: c_int = 3;
+const SELECT_COLUMN_SELECTOR: c_int = 4;
+
+/// The document the registered functions run on, wrapping the connection they
+/// are registered on. It is built once so its prepared statements are cached
+/// across calls, and is dropped through `Functions` before that connection is
+/// closed, as SQLite refuses to close with statements still open.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedDb(Arc<Mutex<Option<DocumentDb>>>);
+
+impl SharedDb {
+    fn with<T>(&self, f: impl FnOnce(&DocumentDb) -> Result<T>) -> Result<T> {
+        let db = match self.0.try_lock() {
+            Ok(x) => x,
+            Err(TryLockError::Poisoned(x)) => x.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::UserFunctionError(
+                    "xml functions cannot be called from within each other".into(),
+                ))
+            }
+        };
+
+        match db.as_ref() {
+            Some(db) => f(db),
+            None => Err(Error::UserFunctionError("document is closed".into())),
+        }
+    }
+}
+
+/// Keeps the functions' document alive for as long as the owning `DocumentDb`.
+#[derive(Debug)]
+pub(crate) struct Functions(SharedDb);
+
+impl Drop for Functions {
+    fn drop(&mut self) {
+        let mut db = match self.0 .0.lock() {
+            Ok(x) => x,
+            Err(e) => e.into_inner(),
+        };
+        db.take();
+    }
+}
+
+/// Registers the `xml_*` functions and the `xml_select` table-valued function
+/// on a document's connection.
+pub(crate) fn register(conn: &Connection, options: &ParseOptions) -> Result<Functions> {
+    // The functions only read the flags, and must not keep the caller's
+    // progress channel or cancel token alive.
+    let options = ParseOptions {
+        progress: None,
+        cancel: None,
+        ..options.clone()
+    };
+    let shared = SharedDb(Arc::new(Mutex::new(Some(unsafe {
+        DocumentDb::borrowed(conn.handle(), options)?
+    }))));
+
+    let db = shared.clone();
+    conn.create_scalar_function("xml_text", 1, flags(), move |ctx| {
+        let node_id = match ctx.get::<Option<usize>>(0)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        db.with(|db| db.text_content(node_id).map(Some))
+    })?;
+
+    let db = shared.clone();
+    conn.create_scalar_function("xml_path", 1, flags(), move |ctx| {
+        let node_id = match ctx.get::<Option<usize>>(0)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        db.with(|db| db.path(node_id).optional())
+    })?;
+
+    let db = shared.clone();
+    conn.create_scalar_function("xml_serialize", 1, flags(), move |ctx| {
+        let node_id = match ctx.get::<Option<usize>>(0)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        db.with(|db| db.subtree_to_string(node_id, &Config::default()).optional())
+    })?;
+
+    let db = shared.clone();
+    conn.create_scalar_function("xml_attr", 2, flags(), move |ctx| {
+        let (node_id, name) = match (ctx.get::<Option<usize>>(0)?, ctx.get::<Option<String>>(1)?) {
+            (Some(node_id), Some(name)) => (node_id, name),
+            _ => return Ok(None),
+        };
+        db.with(|db| Ok(db.attr_by_name(node_id, &name, None)?.map(|x| x.value)))
+    })?;
+
+    conn.create_module(
+        "xml_select",
+        eponymous_only_module::<SelectTab>(),
+        Some(shared.clone()),
+    )?;
+
+    Ok(Functions(shared))
+}
+
+fn flags() -> FunctionFlags {
+    FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC
+}
+
+#[repr(C)]
+struct SelectTab {
+    base: ffi::sqlite3_vtab,
+    db: SharedDb,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for SelectTab {
+    type Aux = SharedDb;
+    type Cursor = SelectTabCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&SharedDb>,
+        _args: &[&[u8]],
+    ) -> Result<(String, SelectTab)> {
+        let vtab = SelectTab {
+            base: ffi::sqlite3_vtab::default(),
+            db: aux
+                .cloned()
+                .expect("xml_select is registered with a document"),
+        };
+        db.config(VTabConfig::Innocuous)?;
+        Ok((
+            "CREATE TABLE x(node_id, attr_id, value, root HIDDEN, selector HIDDEN)".to_owned(),
+            vtab,
+        ))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
+        let mut root = None;
+        let mut selector = None;
+
+        for (i, constraint) in info.constraints().enumerate() {
+            if !constraint.is_usable()
+                || constraint.operator() != IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+            {
+                continue;
+            }
+
+            match constraint.column() {
+                SELECT_COLUMN_ROOT => root = Some(i),
+                SELECT_COLUMN_SELECTOR => selector = Some(i),
+                _ => {}
+            }
+        }
+
+        // Both arguments are required, as in `xml_select(node_id, css)`.
+        let (root, selector) = match (root, selector) {
+            (Some(root), Some(selector)) => (root, selector),
+            _ => {
+                return Err(Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_CONSTRAINT),
+                    Some("xml_select requires a node id and a selector".to_string()),
+                ))
+            }
+        };
+
+        for (argv_index, i) in [(1, root), (2, selector)] {
+            let mut usage = info.constraint_usage(i);
+            usage.set_argv_index(argv_index);
+            usage.set_omit(true);
+        }
+
+        info.set_estimated_cost(1000.0);
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> Result<SelectTabCursor<'vtab>> {
+        Ok(SelectTabCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            db: &self.db,
+            root: 0,
+            selector: String::new(),
+            rows: vec![],
+            pos: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+#[repr(C)]
+struct SelectTabCursor<'vtab> {
+    base: ffi::sqlite3_vtab_cursor,
+    db: &'vtab SharedDb,
+    root: usize,
+    selector: String,
+    rows: Vec<(Option<usize>, Option<usize>, Option<String>)>,
+    pos: usize,
+    phantom: PhantomData<&'vtab SelectTab>,
+}
+
+unsafe impl VTabCursor for SelectTabCursor<'_> {
+    fn filter(&mut self, _idx_num: c_int, _idx_str: Option<&str>, args: &Values<'_>) -> Result<()> {
+        self.root = args.get(0)?;
+        self.selector = args.get(1)?;
+        self.pos = 0;
+
+        let selector = Selector::new(&self.selector)
+            .map_err(|e| Error::ModuleError(format!("invalid selector: {e:?}")))?;
+
+        let root = self.root;
+        self.rows = self
+            .db
+            .with(|db| selector.select_all_from(db, root))?
+            .into_iter()
+            .map(|x| match x {
+                model::Selected::Element(x) => (Some(x.node_id), None, None),
+                model::Selected::Attr(x) => (None, Some(x.attr_id), Some(x.value)),
+                model::Selected::Text(x) => (Some(x.node_id), None, Some(x.value)),
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut vtab::Context, i: c_int) -> Result<()> {
+        let (node_id, attr_id, value) = &self.rows[self.pos];
+
+        match i {
+            0 => ctx.set_result(node_id),
+            1 => ctx.set_result(attr_id),
+            2 => ctx.set_result(value),
+            SELECT_COLUMN_ROOT => ctx.set_result(&self.root),
+            _ => ctx.set_result(&self.selector),
+        }
+    }
+
+    fn rowid(&self) -> Result<i64> {
+        Ok(self.pos as i64 + 1)
+    }
+}
@@ -0,0 +1,181 @@
+use html5ever::{tendril::TendrilSink, ParseOpts};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+use crate::{
+    builder::{DocumentDbBuilder, InsertAttr, InsertError, InsertNode},
+    document::{DocumentDb, NodeType},
+    parse::{emit_text, parse_start_event, write_message, Error, Message, ParserState},
+    progress::{Monitor, Phase, PROGRESS_INTERVAL},
+};
+
+enum Step {
+    Enter(Handle),
+    Exit,
+}
+
+/// Builds the document from tag soup using the HTML5 tree construction rules,
+/// so implied and misnested tags end up where a browser would put them.
+/// html5ever does not report source offsets, so every buffer position is 0.
+pub(crate) fn parse(
+    mut doc_db: DocumentDb,
+    input: &str,
+    monitor: Monitor,
+) -> Result<DocumentDb, Error> {
+    let options = doc_db.options.clone();
+    let bytes_total = input.len();
+
+    monitor.report(Phase::Tokenize, 0, bytes_total, 0, None);
+    let dom = html5ever::parse_document(RcDom::default(), ParseOpts::default()).one(input);
+    monitor.check()?;
+
+    let mut parser_state = ParserState::default();
+    let mut node_id_count = 2usize;
+
+    {
+        let db = DocumentDbBuilder::new(doc_db.conn.transaction()?, options.infer_types);
+        let mut emit = |msg| write_message(&db, msg).map_err(Error::from);
+
+        for error in dom.errors.iter() {
+            emit(Message::InsertError(InsertError::new(
+                0,
+                0,
+                error.to_string(),
+            )))?;
+        }
+
+        let mut last_report = 0;
+        let mut steps = dom
+            .document
+            .children
+            .borrow()
+            .iter()
+            .rev()
+            .cloned()
+            .map(Step::Enter)
+            .collect::<Vec<_>>();
+
+        while let Some(step) = steps.pop() {
+            let handle = match step {
+                Step::Enter(x) => x,
+                Step::Exit => {
+                    parser_state.pop();
+                    continue;
+                }
+            };
+
+            match &handle.data {
+                NodeData::Document => {}
+                NodeData::Doctype { name, .. } => {
+                    emit(Message::InsertNode(InsertNode::new(
+                        node_id_count,
+                        parser_state.parent_node_id(),
+                        NodeType::Doctype,
+                        None,
+                        None,
+                        Some(name.to_string()),
+                        0,
+                        parser_state.current_order(),
+                    )))?;
+                    node_id_count += 1;
+                    parser_state.increment_order();
+                }
+                NodeData::Text { contents } => {
+                    emit_text(
+                        &contents.borrow(),
+                        0,
+                        &mut parser_state,
+                        &mut node_id_count,
+                        &options,
+                        &mut emit,
+                    )?;
+                }
+                NodeData::Comment { contents } => {
+                    emit(Message::InsertNode(InsertNode::new(
+                        node_id_count,
+                        parser_state.parent_node_id(),
+                        NodeType::Comment,
+                        None,
+                        None,
+                        Some(if options.ignore_whitespace {
+                            contents.trim().to_string()
+                        } else {
+                            contents.to_string()
+                        }),
+                        0,
+                        parser_state.current_order(),
+                    )))?;
+                    node_id_count += 1;
+                    parser_state.increment_order();
+                }
+                NodeData::ProcessingInstruction { target, contents } => {
+                    emit(Message::InsertNode(InsertNode::new(
+                        node_id_count,
+                        parser_state.parent_node_id(),
+                        NodeType::ProcessingInstruction,
+                        None,
+                        None,
+                        Some(format!("{target} {contents}")),
+                        0,
+                        parser_state.current_order(),
+                    )))?;
+                    node_id_count += 1;
+                    parser_state.increment_order();
+                }
+                NodeData::Element {
+                    name,
+                    attrs,
+                    template_contents,
+                    ..
+                } => {
+                    // The tree builder has already lowercased element and
+                    // attribute names.
+                    parse_start_event(
+                        &name.local,
+                        None,
+                        0,
+                        &mut parser_state,
+                        &mut emit,
+                        &mut node_id_count,
+                    )?;
+
+                    for attr in attrs.borrow().iter() {
+                        emit(Message::InsertAttr(InsertAttr::new(
+                            parser_state.parent_node_id(),
+                            attr.name.prefix.as_ref().map(|x| x.to_string()),
+                            attr.name.local.to_string(),
+                            attr.value.to_string(),
+                            0,
+                            parser_state.current_order(),
+                        )))?;
+                        parser_state.increment_order();
+                    }
+
+                    steps.push(Step::Exit);
+
+                    // The content of a template is kept as ordinary children.
+                    let children = match template_contents.borrow().as_ref() {
+                        Some(x) => x.children.borrow().clone(),
+                        None => handle.children.borrow().clone(),
+                    };
+                    steps.extend(children.into_iter().rev().map(Step::Enter));
+                }
+            }
+
+            if node_id_count - last_report >= PROGRESS_INTERVAL {
+                last_report = node_id_count;
+                monitor.check()?;
+                monitor.report(Phase::Insert, 0, bytes_total, node_id_count - 2, None);
+            }
+        }
+
+        let count = node_id_count - 2;
+        monitor.check()?;
+        monitor.report(Phase::Index, bytes_total, bytes_total, count, Some(count));
+        db.add_indexes()?;
+        db.add_paths()?;
+        db.commit()?;
+        monitor.report(Phase::Done, bytes_total, bytes_total, count, Some(count));
+    }
+
+    Ok(doc_db)
+}
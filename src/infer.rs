@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{cmp::Ordering, str::FromStr};
 
 // use base64::Engine;
 use uuid::Uuid;
@@ -78,6 +78,26 @@ impl Inferred {
             Inferred::Json(_) => InferredType::Json,
         }
     }
+
+    /// Orders two values of compatible types. Ints and floats compare as
+    /// numbers, and a date compares with the date part of a datetime.
+    pub fn compare(&self, other: &Inferred) -> Option<Ordering> {
+        match (self, other) {
+            (Inferred::Int(a), Inferred::Int(b)) => Some(a.cmp(b)),
+            (Inferred::Int(a), Inferred::Float(b)) => (*a as f64).partial_cmp(b),
+            (Inferred::Float(a), Inferred::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Inferred::Float(a), Inferred::Float(b)) => a.partial_cmp(b),
+            (Inferred::Boolean(a), Inferred::Boolean(b)) => Some(a.cmp(b)),
+            (Inferred::DateTime(a), Inferred::DateTime(b)) => a.partial_cmp(b),
+            (Inferred::DateTime(a), Inferred::Date(b)) => a.date.partial_cmp(b),
+            (Inferred::Date(a), Inferred::DateTime(b)) => a.partial_cmp(&b.date),
+            (Inferred::Date(a), Inferred::Date(b)) => a.partial_cmp(b),
+            (Inferred::Time(a), Inferred::Time(b)) => a.partial_cmp(b),
+            (Inferred::Duration(a), Inferred::Duration(b)) => a.partial_cmp(b),
+            (Inferred::Uuid(a), Inferred::Uuid(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
 }
 
 impl InferredType {
@@ -0,0 +1,637 @@
+use std::{borrow::Cow, collections::HashSet, str::FromStr};
+
+use rusqlite::{OptionalExtension, Result};
+use serde_json::{Map, Number, Value};
+
+use crate::{
+    builder::{DocumentDbBuilder, InsertAttr},
+    c14n::decode_references,
+    infer::{infer_type, Inferred},
+    model::{qualified_name, split_name, Attr, Element, Node},
+    parse::{emit_text, mutate_text, parse_start_event, write_message, Message, ParserState},
+    progress::{Monitor, Phase},
+    DocumentDb, Error, ParseOptions,
+};
+
+/// How elements, attributes and text are mapped to JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JsonConvention {
+    /// Attributes as `@name` and text as `#text`, with elements that only
+    /// contain text becoming their value.
+    #[default]
+    Simple,
+    /// Every element is an object, attributes are `@name`, text is `$` and
+    /// namespace declarations are collected under `@xmlns`.
+    BadgerFish,
+    /// Attributes are dropped and the root element's name is omitted. When
+    /// importing, the root element is named `root`.
+    Parker,
+    /// Every element is an array of its name, an optional attribute object
+    /// and its children in document order.
+    JsonMl,
+}
+
+impl JsonConvention {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JsonConvention::Simple => "simple",
+            JsonConvention::BadgerFish => "badgerfish",
+            JsonConvention::Parker => "parker",
+            JsonConvention::JsonMl => "jsonml",
+        }
+    }
+}
+
+impl FromStr for JsonConvention {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(JsonConvention::Simple),
+            "badgerfish" => Ok(JsonConvention::BadgerFish),
+            "parker" => Ok(JsonConvention::Parker),
+            "jsonml" => Ok(JsonConvention::JsonMl),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JsonConfig {
+    pub convention: JsonConvention,
+    /// Values that infer as integers, floats or booleans become JSON numbers
+    /// and booleans instead of strings.
+    pub typed: bool,
+    /// Elements whose path repeats under any parent in the document are
+    /// always arrays, even where they only occur once.
+    pub detect_arrays: bool,
+}
+
+struct Converter<'a> {
+    db: &'a DocumentDb,
+    config: &'a JsonConfig,
+    arrays: HashSet<String>,
+    decode: bool,
+}
+
+/// The attributes, text and child elements of an element. Whitespace-only
+/// text is dropped and the remaining text and CDATA is concatenated.
+struct Content {
+    attrs: Vec<Attr>,
+    text: Option<String>,
+    children: Vec<Element>,
+}
+
+impl DocumentDb {
+    /// Converts the root element when `node_id` is 0, or the element at
+    /// `node_id`, to JSON.
+    pub fn to_json(&self, node_id: usize, config: &JsonConfig) -> Result<Value> {
+        let element = self.element(if node_id == 0 { 1 } else { node_id })?;
+
+        let arrays = if config.detect_arrays {
+            self.path_summary()?
+                .into_iter()
+                .filter(|x| x.max_per_parent > 1)
+                .map(|x| x.path)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let path = self
+            .conn
+            .query_row(
+                r#"
+                    SELECT paths.path FROM nodes, paths
+                    WHERE nodes.node_id = ?1 AND paths.path_id = nodes.path_id
+                "#,
+                [element.node_id],
+                |r| r.get::<_, String>(0),
+            )
+            .optional()?
+            .unwrap_or_default();
+
+        let c = Converter {
+            db: self,
+            config,
+            arrays,
+            decode: self.options.raw_values(),
+        };
+
+        let value = match config.convention {
+            JsonConvention::Simple => c.simple(&element, &path)?,
+            JsonConvention::BadgerFish => c.badgerfish(&element, &path)?,
+            JsonConvention::Parker => return c.parker(&element, &path),
+            JsonConvention::JsonMl => return c.jsonml(&element),
+        };
+
+        let mut map = Map::new();
+        map.insert(
+            qualified_name(element.ns.as_deref(), &element.name).into_owned(),
+            value,
+        );
+        Ok(Value::Object(map))
+    }
+}
+
+impl Converter<'_> {
+    fn simple(&self, element: &Element, path: &str) -> Result<Value> {
+        let content = self.content(element)?;
+
+        if content.attrs.is_empty() && content.children.is_empty() {
+            return Ok(content.text.map_or(Value::Null, |x| self.value(&x)));
+        }
+
+        let mut map = Map::new();
+        for attr in &content.attrs {
+            let name = qualified_name(attr.ns.as_deref(), &attr.name);
+            map.insert(format!("@{name}"), self.attr_value(attr));
+        }
+        if let Some(text) = content.text {
+            map.insert("#text".to_string(), self.value(&text));
+        }
+        for child in &content.children {
+            let (name, child_path) = child_path(child, path);
+            let value = self.simple(child, &child_path)?;
+            self.insert_child(&mut map, name, &child_path, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+
+    fn badgerfish(&self, element: &Element, path: &str) -> Result<Value> {
+        let content = self.content(element)?;
+
+        let mut map = Map::new();
+        let mut ns = Map::new();
+        for attr in &content.attrs {
+            match (attr.ns.as_deref(), attr.name.as_str()) {
+                (None, "xmlns") => {
+                    ns.insert(
+                        "$".to_string(),
+                        Value::String(self.decoded(&attr.value).into()),
+                    );
+                }
+                (Some("xmlns"), prefix) => {
+                    ns.insert(
+                        prefix.to_string(),
+                        Value::String(self.decoded(&attr.value).into()),
+                    );
+                }
+                (ns, name) => {
+                    let name = qualified_name(ns, name);
+                    map.insert(format!("@{name}"), self.attr_value(attr));
+                }
+            }
+        }
+        if !ns.is_empty() {
+            map.insert("@xmlns".to_string(), Value::Object(ns));
+        }
+        if let Some(text) = content.text {
+            map.insert("$".to_string(), self.value(&text));
+        }
+        for child in &content.children {
+            let (name, child_path) = child_path(child, path);
+            let value = self.badgerfish(child, &child_path)?;
+            self.insert_child(&mut map, name, &child_path, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+
+    fn parker(&self, element: &Element, path: &str) -> Result<Value> {
+        let content = self.content(element)?;
+
+        if content.children.is_empty() {
+            return Ok(content.text.map_or(Value::Null, |x| self.value(&x)));
+        }
+
+        let mut map = Map::new();
+        for child in &content.children {
+            let (name, child_path) = child_path(child, path);
+            let value = self.parker(child, &child_path)?;
+            self.insert_child(&mut map, name, &child_path, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+
+    fn jsonml(&self, element: &Element) -> Result<Value> {
+        let mut items = vec![Value::String(
+            qualified_name(element.ns.as_deref(), &element.name).into_owned(),
+        )];
+
+        let attrs = self.db.attrs(element.node_id)?;
+        if !attrs.is_empty() {
+            let mut map = Map::new();
+            for attr in &attrs {
+                let name = qualified_name(attr.ns.as_deref(), &attr.name);
+                map.insert(name.into_owned(), self.attr_value(attr));
+            }
+            items.push(Value::Object(map));
+        }
+
+        for child in self.db.child_nodes(element.node_id)? {
+            match child {
+                Node::Element(x) => items.push(self.jsonml(&x)?),
+                Node::Text(x) if !x.value.trim().is_empty() => {
+                    items.push(self.value(&self.decoded(&x.value)));
+                }
+                Node::CData(x) if !x.value.trim().is_empty() => items.push(self.value(&x.value)),
+                _ => {}
+            }
+        }
+
+        Ok(Value::Array(items))
+    }
+
+    fn content(&self, element: &Element) -> Result<Content> {
+        let mut text: Option<String> = None;
+        let mut children = vec![];
+
+        for child in self.db.child_nodes(element.node_id)? {
+            let value = match child {
+                Node::Element(x) => {
+                    children.push(x);
+                    continue;
+                }
+                Node::Text(x) => self.decoded(&x.value).into_owned(),
+                Node::CData(x) => x.value,
+                _ => continue,
+            };
+            if value.trim().is_empty() {
+                continue;
+            }
+            match &mut text {
+                Some(text) => text.push_str(&value),
+                None => text = Some(value),
+            }
+        }
+
+        Ok(Content {
+            attrs: self.db.attrs(element.node_id)?,
+            text,
+            children,
+        })
+    }
+
+    /// Adds a child's value under its name, turning repeated names, and with
+    /// `detect_arrays` every name whose path repeats, into arrays.
+    fn insert_child(&self, map: &mut Map<String, Value>, name: String, path: &str, value: Value) {
+        match map.get_mut(&name) {
+            Some(Value::Array(x)) => x.push(value),
+            Some(existing) => {
+                let previous = existing.take();
+                *existing = Value::Array(vec![previous, value]);
+            }
+            None if self.arrays.contains(path) => {
+                map.insert(name, Value::Array(vec![value]));
+            }
+            None => {
+                map.insert(name, value);
+            }
+        }
+    }
+
+    fn attr_value(&self, attr: &Attr) -> Value {
+        self.value(&self.decoded(&attr.value))
+    }
+
+    fn decoded<'b>(&self, value: &'b str) -> Cow<'b, str> {
+        if self.decode {
+            decode_references(value)
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+
+    fn value(&self, value: &str) -> Value {
+        if !self.config.typed {
+            return Value::String(value.to_string());
+        }
+
+        match infer_type(value.trim()) {
+            Inferred::Boolean(x) => Value::Bool(x),
+            Inferred::Int(x) => Value::Number(x.into()),
+            Inferred::Float(x) => match value.trim().parse::<i64>() {
+                Ok(x) => Value::Number(x.into()),
+                Err(_) => Number::from_f64(x)
+                    .map(Value::Number)
+                    .unwrap_or_else(|| Value::String(value.to_string())),
+            },
+            _ => Value::String(value.to_string()),
+        }
+    }
+}
+
+fn child_path(child: &Element, path: &str) -> (String, String) {
+    let name = qualified_name(child.ns.as_deref(), &child.name).into_owned();
+    let child_path = format!("{path}/{name}");
+    (name, child_path)
+}
+
+struct Importer<'a, F> {
+    convention: JsonConvention,
+    options: &'a ParseOptions,
+    parser_state: ParserState,
+    node_id_count: usize,
+    emit: F,
+}
+
+/// Builds the document from JSON using the inverse of the export mapping for
+/// `convention`. Values are stored decoded and every buffer position is 0.
+pub(crate) fn parse(
+    doc_db: DocumentDb,
+    input: &str,
+    convention: JsonConvention,
+    monitor: Monitor,
+) -> Result<DocumentDb, Error> {
+    let bytes_total = input.len();
+
+    monitor.report(Phase::Tokenize, 0, bytes_total, 0, None);
+    let value = serde_json::from_str::<Value>(input)?;
+    monitor.check()?;
+
+    import(doc_db, &value, convention, monitor, bytes_total)
+}
+
+/// Imports a value that is already in memory. `bytes_total` is the size of
+/// its source, only used for progress reports.
+pub(crate) fn import(
+    mut doc_db: DocumentDb,
+    value: &Value,
+    convention: JsonConvention,
+    monitor: Monitor,
+    bytes_total: usize,
+) -> Result<DocumentDb, Error> {
+    let options = doc_db.options.clone();
+
+    {
+        let db = DocumentDbBuilder::new(doc_db.conn.transaction()?, options.infer_types);
+        let mut importer = Importer {
+            convention,
+            options: &options,
+            parser_state: ParserState::default(),
+            node_id_count: 2,
+            emit: |msg| write_message(&db, msg).map_err(Error::from),
+        };
+
+        importer.document(value)?;
+
+        let count = importer.node_id_count - 2;
+        monitor.check()?;
+        monitor.report(Phase::Index, bytes_total, bytes_total, count, Some(count));
+        db.add_indexes()?;
+        db.add_paths()?;
+        db.commit()?;
+        monitor.report(Phase::Done, bytes_total, bytes_total, count, Some(count));
+    }
+
+    Ok(doc_db)
+}
+
+impl<F> Importer<'_, F>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
+    fn document(&mut self, value: &Value) -> Result<(), Error> {
+        match (self.convention, value) {
+            (JsonConvention::JsonMl, Value::Array(items))
+                if matches!(items.first(), Some(Value::String(_))) =>
+            {
+                self.jsonml(items)
+            }
+            (JsonConvention::Simple | JsonConvention::BadgerFish, Value::Object(map))
+                if map.len() == 1 && !map.values().any(Value::is_array) =>
+            {
+                let (name, value) = map.iter().next().unwrap();
+                self.element(name, value)
+            }
+            // Anything else becomes the content of an element named `root`.
+            (_, value) => {
+                self.start("root")?;
+                self.content(value)?;
+                self.parser_state.pop();
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds an element for each item of an array, or a single element. An
+    /// array nested in an array becomes one element with an `item` child per
+    /// item, so its items are not merged into the outer array.
+    fn element(&mut self, name: &str, value: &Value) -> Result<(), Error> {
+        if let Value::Array(items) = value {
+            for item in items {
+                match item {
+                    Value::Array(_) => {
+                        self.start(name)?;
+                        self.content(item)?;
+                        self.parser_state.pop();
+                    }
+                    item => self.element(name, item)?,
+                }
+            }
+            return Ok(());
+        }
+
+        self.start(name)?;
+        self.content(value)?;
+        self.parser_state.pop();
+        Ok(())
+    }
+
+    fn content(&mut self, value: &Value) -> Result<(), Error> {
+        let map = match value {
+            Value::Object(map) => map,
+            Value::Array(items) => {
+                for item in items {
+                    match self.convention {
+                        JsonConvention::JsonMl => self.jsonml_child(item)?,
+                        _ => self.element("item", item)?,
+                    }
+                }
+                return Ok(());
+            }
+            value => return self.text(value),
+        };
+
+        let (text_key, attr_prefix) = match self.convention {
+            JsonConvention::Simple => (Some("#text"), Some('@')),
+            JsonConvention::BadgerFish => (Some("$"), Some('@')),
+            JsonConvention::Parker | JsonConvention::JsonMl => (None, None),
+        };
+
+        // Attributes come first so they precede the children in order.
+        for (key, value) in map {
+            if self.convention == JsonConvention::BadgerFish && key == "@xmlns" {
+                if let Value::Object(ns) = value {
+                    for (prefix, uri) in ns {
+                        match prefix.as_str() {
+                            "$" => self.attr("xmlns", uri)?,
+                            prefix => self.attr(&format!("xmlns:{prefix}"), uri)?,
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            if let Some(name) = attr_prefix.and_then(|x| key.strip_prefix(x)) {
+                self.attr(name, value)?;
+            }
+        }
+
+        for (key, value) in map {
+            if Some(key.as_str()) == text_key {
+                self.text(value)?;
+            } else if attr_prefix.is_none_or(|x| !key.starts_with(x)) {
+                self.element(key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn jsonml(&mut self, items: &[Value]) -> Result<(), Error> {
+        let (name, rest) = match items.split_first() {
+            Some((Value::String(name), rest)) => (name, rest),
+            _ => {
+                for item in items {
+                    self.jsonml_child(item)?;
+                }
+                return Ok(());
+            }
+        };
+
+        self.start(name)?;
+
+        let rest = match rest.split_first() {
+            Some((Value::Object(attrs), rest)) => {
+                for (name, value) in attrs {
+                    self.attr(name, value)?;
+                }
+                rest
+            }
+            _ => rest,
+        };
+
+        for item in rest {
+            self.jsonml_child(item)?;
+        }
+
+        self.parser_state.pop();
+        Ok(())
+    }
+
+    fn jsonml_child(&mut self, value: &Value) -> Result<(), Error> {
+        match value {
+            Value::Array(items) => self.jsonml(items),
+            value => self.text(value),
+        }
+    }
+
+    fn start(&mut self, name: &str) -> Result<(), Error> {
+        let name = mutate_text(name, self.options);
+        check_name(&name)?;
+        let (prefix, local) = split_name(&name);
+        parse_start_event(
+            local,
+            prefix,
+            0,
+            &mut self.parser_state,
+            &mut self.emit,
+            &mut self.node_id_count,
+        )
+    }
+
+    fn attr(&mut self, name: &str, value: &Value) -> Result<(), Error> {
+        let name = mutate_text(name, self.options);
+        check_name(&name)?;
+        let (prefix, local) = split_name(&name);
+        (self.emit)(Message::InsertAttr(InsertAttr::new(
+            self.parser_state.parent_node_id(),
+            prefix.map(str::to_string),
+            local.to_string(),
+            scalar_text(value).unwrap_or_default(),
+            0,
+            self.parser_state.current_order(),
+        )))?;
+        self.parser_state.increment_order();
+        Ok(())
+    }
+
+    fn text(&mut self, value: &Value) -> Result<(), Error> {
+        match scalar_text(value) {
+            Some(text) => emit_text(
+                &text,
+                0,
+                &mut self.parser_state,
+                &mut self.node_id_count,
+                self.options,
+                &mut self.emit,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The text of a scalar, `None` for `null` and the JSON of arrays and objects.
+fn scalar_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(x) => Some(x.clone()),
+        Value::Bool(x) => Some(x.to_string()),
+        Value::Number(x) => Some(x.to_string()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Keys become element and attribute names as they are, so they have to be
+/// valid XML names with at most one prefix.
+fn check_name(name: &str) -> Result<(), Error> {
+    let is_ncname = |x: &str| {
+        let mut chars = x.chars();
+        chars.next().is_some_and(is_name_start_char) && chars.all(is_name_char)
+    };
+
+    let valid = match name.split_once(':') {
+        Some((prefix, local)) => is_ncname(prefix) && is_ncname(local),
+        None => is_ncname(name),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidName(name.to_string()))
+    }
+}
+
+/// `NameStartChar` from the XML 1.0 grammar, without `:`.
+fn is_name_start_char(ch: char) -> bool {
+    matches!(
+        ch,
+        'A'..='Z'
+            | '_'
+            | 'a'..='z'
+            | '\u{C0}'..='\u{D6}'
+            | '\u{D8}'..='\u{F6}'
+            | '\u{F8}'..='\u{2FF}'
+            | '\u{370}'..='\u{37D}'
+            | '\u{37F}'..='\u{1FFF}'
+            | '\u{200C}'..='\u{200D}'
+            | '\u{2070}'..='\u{218F}'
+            | '\u{2C00}'..='\u{2FEF}'
+            | '\u{3001}'..='\u{D7FF}'
+            | '\u{F900}'..='\u{FDCF}'
+            | '\u{FDF0}'..='\u{FFFD}'
+            | '\u{10000}'..='\u{EFFFF}'
+    )
+}
+
+fn is_name_char(ch: char) -> bool {
+    is_name_start_char(ch)
+        || matches!(
+            ch,
+            '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}'
+        )
+}
@@ -1,18 +1,44 @@
 mod builder;
+mod c14n;
+#[cfg(feature = "arrow")]
+mod columnar;
+pub mod de;
 mod document;
+mod events;
+mod functions;
+mod html;
 mod infer;
+mod json;
 pub mod model;
+mod parallel;
 mod parse;
+mod pool;
+mod preserve;
+mod progress;
+mod query;
+mod recover;
 pub mod redact;
 mod select;
+pub mod ser;
+mod table;
 mod writer;
 
 use std::path::Path;
 
+pub use c14n::C14nConfig;
+#[cfg(feature = "arrow")]
+pub use columnar::write_parquet;
 pub use document::{DocumentDb, NodeType};
+pub use events::Events;
 pub use infer::{Inferred, InferredType};
+pub use json::{JsonConfig, JsonConvention};
 pub use parse::{Error, ParseOptions};
+pub use pool::{DocumentPool, PooledDocumentDb};
+pub use progress::{CancelToken, Phase, Progress};
+pub use query::{QueryResult, Value};
 pub use select::Selector;
+pub use table::{Column, TableConfig};
+pub use writer::{AttrOrder, Config, EntityMode, LineEnding, QuoteStyle};
 
 pub fn parse_path_to_disk<P: AsRef<Path>, Q: AsRef<Path>>(
     db_path: P,
@@ -51,7 +77,11 @@ pub fn parse_to_disk<P: AsRef<Path>>(
     options: ParseOptions,
 ) -> Result<DocumentDb, Error> {
     let db = DocumentDb::create(db_path.as_ref(), options)?;
-    parse::parse(db, input)
+    parse::parse(db, input).map_err(|e| {
+        // Don't leave a partially written database behind.
+        let _ = std::fs::remove_file(db_path.as_ref());
+        e
+    })
 }
 
 pub fn parse_to_temp_file(input: &str, options: ParseOptions) -> Result<DocumentDb, Error> {
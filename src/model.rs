@@ -1,4 +1,6 @@
-use crate::NodeType;
+use std::borrow::Cow;
+
+use crate::{InferredType, NodeType};
 
 #[derive(Debug, Clone)]
 pub struct Element {
@@ -33,6 +35,111 @@ pub struct Attr {
     pub value: String,
 }
 
+#[derive(Debug, Clone)]
+pub enum Selected {
+    Element(Element),
+    Attr(Attr),
+    Text(Text),
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub error_id: usize,
+    pub parent_node_id: usize,
+    pub buffer_position: u64,
+    pub message: String,
+}
+
+/// One distinct element path, as reported by `DocumentDb::path_summary`.
+#[derive(Debug, Clone)]
+pub struct PathSummary {
+    pub path_id: usize,
+    pub path: String,
+    pub depth: usize,
+    pub count: usize,
+    /// Fewest occurrences under a single parent, counting parents on the
+    /// parent path that have none.
+    pub min_per_parent: usize,
+    pub max_per_parent: usize,
+    /// How often each type was inferred for the text of these elements, most
+    /// common first.
+    pub types: Vec<(InferredType, usize)>,
+}
+
+/// Counts describing the shape of a document, as reported by
+/// `DocumentDb::stats`. The `Display` impl formats it as a plain text report.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub node_counts: Vec<(NodeType, usize)>,
+    /// Qualified element names, most frequent first.
+    pub element_names: Vec<(String, usize)>,
+    /// Qualified attribute names, most frequent first.
+    pub attr_names: Vec<(String, usize)>,
+    /// Number of elements at each depth, the root being at depth 1.
+    pub depths: Vec<(usize, usize)>,
+    /// Most child elements under a single node.
+    pub max_fan_out: usize,
+    /// Size of all text and CDATA in bytes.
+    pub text_bytes: usize,
+    /// Types inferred from the text of elements, by element name.
+    pub element_types: Vec<(String, Vec<(InferredType, usize)>)>,
+    pub attr_types: Vec<(String, Vec<(InferredType, usize)>)>,
+}
+
+impl Stats {
+    pub fn max_depth(&self) -> usize {
+        self.depths.last().map_or(0, |x| x.0)
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn types(types: &[(InferredType, usize)]) -> String {
+            types
+                .iter()
+                .map(|(ty, count)| format!("{}={count}", ty.as_str()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        writeln!(f, "Nodes:")?;
+        for (ty, count) in &self.node_counts {
+            writeln!(f, "  {ty:?}: {count}")?;
+        }
+
+        writeln!(f, "Elements:")?;
+        for (name, count) in &self.element_names {
+            writeln!(f, "  {name}: {count}")?;
+        }
+
+        writeln!(f, "Attributes:")?;
+        for (name, count) in &self.attr_names {
+            writeln!(f, "  {name}: {count}")?;
+        }
+
+        writeln!(f, "Depths:")?;
+        for (depth, count) in &self.depths {
+            writeln!(f, "  {depth}: {count}")?;
+        }
+
+        writeln!(f, "Max depth: {}", self.max_depth())?;
+        writeln!(f, "Max fan-out: {}", self.max_fan_out)?;
+        writeln!(f, "Text bytes: {}", self.text_bytes)?;
+
+        writeln!(f, "Element types:")?;
+        for (name, x) in &self.element_types {
+            writeln!(f, "  {name}: {}", types(x))?;
+        }
+
+        writeln!(f, "Attribute types:")?;
+        for (name, x) in &self.attr_types {
+            writeln!(f, "  {name}: {}", types(x))?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RawNode {
     pub node_id: usize,
@@ -129,3 +236,35 @@ impl Node {
         }
     }
 }
+
+/// A parse event reconstructed from the tables, as yielded by
+/// `DocumentDb::events`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    StartDocument,
+    EndDocument,
+    StartElement { element: Element, attrs: Vec<Attr> },
+    EndElement(Element),
+    Text(Text),
+    CData(CData),
+    Comment(Comment),
+    ProcessingInstruction(ProcessingInstruction),
+    Declaration(Declaration),
+    Doctype(Doctype),
+}
+
+/// `prefix:name`, or just the name without a prefix.
+pub(crate) fn qualified_name<'a>(ns: Option<&str>, name: &'a str) -> Cow<'a, str> {
+    match ns {
+        Some(ns) => Cow::Owned(format!("{ns}:{name}")),
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// Splits `prefix:name` into its prefix and local name.
+pub(crate) fn split_name(name: &str) -> (Option<&str>, &str) {
+    match name.split_once(':') {
+        Some((prefix, local)) if !prefix.is_empty() => (Some(prefix), local),
+        _ => (None, name),
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+use crate::{
+    builder::{merge_chunk, DocumentDbBuilder},
+    document::DocumentDb,
+    parse::{handle_token, write_message, Error, ParseOptions, ParserState, ParserStateValue},
+    progress::{Monitor, Phase, PROGRESS_INTERVAL},
+};
+
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+struct Chunk {
+    dir: tempfile::TempDir,
+    end: usize,
+    node_count: usize,
+    order_count: usize,
+}
+
+pub(crate) fn parse(
+    mut doc_db: DocumentDb,
+    input: &str,
+    monitor: Monitor,
+) -> Result<DocumentDb, Error> {
+    let options = doc_db.options.clone();
+    let threads = options.threads;
+    let bytes_total = input.len();
+
+    let mut parser_state = ParserState::default();
+    let mut node_id_count = 2usize;
+
+    // Everything up to the end of the root start tag is parsed on this thread.
+    let mut content_start = None;
+    {
+        let db = DocumentDbBuilder::new(doc_db.conn.transaction()?, options.infer_types);
+        let mut emit = |msg| write_message(&db, msg).map_err(Error::from);
+
+        for token in Tokenizer::from(input) {
+            let token = token?;
+            let root_open = match token {
+                Token::ElementEnd {
+                    end: ElementEnd::Open,
+                    span,
+                } if matches!(parser_state.current(), ParserStateValue::Root) => Some(span.end()),
+                _ => None,
+            };
+
+            handle_token(
+                token,
+                &mut parser_state,
+                &mut node_id_count,
+                &options,
+                &mut emit,
+            )?;
+
+            if root_open.is_some() {
+                content_start = root_open;
+                break;
+            }
+        }
+
+        if content_start.is_none() {
+            db.add_indexes()?;
+            db.add_paths()?;
+        }
+        db.commit()?;
+    }
+
+    let content_start = match content_start {
+        Some(x) => x,
+        None => return Ok(doc_db),
+    };
+
+    let (chunks, content_end) = split_body(input, content_start, CHUNK_SIZE)?;
+    let chunk_count = chunks.len();
+
+    // Each chunk is written to its own temporary database in parallel, and
+    // then copied into the document in order with its ids and orders offset.
+    std::thread::scope(|scope| {
+        let (job_tx, job_rx) = crossbeam_channel::unbounded::<(usize, Range<usize>)>();
+        let (result_tx, result_rx) = crossbeam_channel::unbounded();
+
+        for _ in 0..threads {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            let options = &options;
+            let monitor = &monitor;
+            scope.spawn(move || {
+                for (i, range) in job_rx.iter() {
+                    if result_tx
+                        .send((i, parse_chunk(input, range, options, monitor)))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        // Only a bounded number of chunks are in flight so finished chunk
+        // databases don't pile up on disk ahead of the merge.
+        let mut jobs = chunks.into_iter().enumerate();
+        for job in jobs.by_ref().take(threads * 2) {
+            job_tx.send(job).unwrap();
+        }
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+
+        while next < chunk_count {
+            let (i, result) = result_rx.recv().expect("chunk worker exited");
+            pending.insert(i, result);
+
+            while let Some(result) = pending.remove(&next) {
+                let chunk: Chunk = result?;
+                monitor.check()?;
+
+                merge_chunk(
+                    &doc_db.conn,
+                    &chunk.dir.path().join("db"),
+                    options.infer_types,
+                    node_id_count - 2,
+                    parser_state.current_order(),
+                )?;
+
+                node_id_count += chunk.node_count;
+                parser_state.advance_order(chunk.order_count);
+                next += 1;
+
+                monitor.report(
+                    Phase::Insert,
+                    chunk.end,
+                    bytes_total,
+                    node_id_count - 2,
+                    None,
+                );
+
+                if let Some(job) = jobs.next() {
+                    job_tx.send(job).unwrap();
+                }
+            }
+        }
+
+        Ok::<_, Error>(())
+    })?;
+
+    // The root end tag and anything after it.
+    let db = DocumentDbBuilder::new(doc_db.conn.transaction()?, options.infer_types);
+    let mut emit = |msg| write_message(&db, msg).map_err(Error::from);
+
+    for token in Tokenizer::from_fragment(input, content_end..input.len()) {
+        let token = token?;
+
+        if let Token::Text { text } = token {
+            if matches!(parser_state.current(), ParserStateValue::Document)
+                && text.trim().is_empty()
+            {
+                continue;
+            }
+        }
+
+        handle_token(
+            token,
+            &mut parser_state,
+            &mut node_id_count,
+            &options,
+            &mut emit,
+        )?;
+    }
+
+    let count = node_id_count - 2;
+    monitor.check()?;
+    monitor.report(Phase::Index, bytes_total, bytes_total, count, Some(count));
+    db.conn
+        .execute_batch(&format!("PRAGMA threads = {threads};"))?;
+    db.add_indexes()?;
+    db.add_paths()?;
+    db.commit()?;
+    monitor.report(Phase::Done, bytes_total, bytes_total, count, Some(count));
+
+    Ok(doc_db)
+}
+
+fn parse_chunk(
+    input: &str,
+    range: Range<usize>,
+    options: &ParseOptions,
+    monitor: &Monitor,
+) -> Result<Chunk, Error> {
+    let mut chunk_db = DocumentDb::create_chunk(options.clone())?;
+    let end = range.end;
+
+    let mut parser_state = ParserState::default();
+    parser_state.push(ParserStateValue::Root);
+    let mut node_id_count = 2usize;
+
+    {
+        let db = DocumentDbBuilder::new(chunk_db.conn.transaction()?, options.infer_types);
+        let mut emit = |msg| write_message(&db, msg).map_err(Error::from);
+
+        let mut last_check = 0;
+        for token in Tokenizer::from_fragment(input, range) {
+            handle_token(
+                token?,
+                &mut parser_state,
+                &mut node_id_count,
+                options,
+                &mut emit,
+            )?;
+
+            if node_id_count - last_check >= PROGRESS_INTERVAL {
+                last_check = node_id_count;
+                monitor.check()?;
+            }
+        }
+
+        db.commit()?;
+    }
+
+    if parser_state.depth() != 1 {
+        return Err(Error::UnclosedRoot);
+    }
+
+    Ok(Chunk {
+        dir: chunk_db.into_temp_dir().unwrap(),
+        end,
+        node_count: node_id_count - 2,
+        order_count: parser_state.current_order(),
+    })
+}
+
+/// Splits the content of the root element into ranges of roughly `chunk_size`
+/// bytes which each hold only complete top-level nodes. Returns the ranges and
+/// the position of the root end tag.
+fn split_body(
+    input: &str,
+    start: usize,
+    chunk_size: usize,
+) -> Result<(Vec<Range<usize>>, usize), Error> {
+    let bytes = input.as_bytes();
+    let mut ranges = vec![];
+    let mut chunk_start = start;
+    let mut pos = start;
+    let mut depth = 0usize;
+
+    loop {
+        pos += input[pos..].find('<').ok_or(Error::UnclosedRoot)?;
+        let rest = &bytes[pos..];
+        let mut at_boundary = false;
+
+        if rest.starts_with(b"<!--") {
+            pos = find_after(input, pos + 4, "-->")?;
+        } else if rest.starts_with(b"<![CDATA[") {
+            pos = find_after(input, pos + 9, "]]>")?;
+        } else if rest.starts_with(b"<?") {
+            pos = find_after(input, pos + 2, "?>")?;
+        } else if rest.starts_with(b"<!") {
+            pos = find_after(input, pos + 2, ">")?;
+        } else if rest.starts_with(b"</") {
+            if depth == 0 {
+                if pos > chunk_start {
+                    ranges.push(chunk_start..pos);
+                }
+                return Ok((ranges, pos));
+            }
+            depth -= 1;
+            pos = find_after(input, pos + 2, ">")?;
+            at_boundary = depth == 0;
+        } else {
+            let (end, is_empty) = start_tag_end(bytes, pos + 1)?;
+            pos = end;
+            if is_empty {
+                at_boundary = depth == 0;
+            } else {
+                depth += 1;
+            }
+        }
+
+        if at_boundary && pos - chunk_start >= chunk_size {
+            ranges.push(chunk_start..pos);
+            chunk_start = pos;
+        }
+    }
+}
+
+fn find_after(input: &str, from: usize, pattern: &str) -> Result<usize, Error> {
+    input[from..]
+        .find(pattern)
+        .map(|i| from + i + pattern.len())
+        .ok_or(Error::UnclosedRoot)
+}
+
+fn start_tag_end(bytes: &[u8], from: usize) -> Result<(usize, bool), Error> {
+    let mut quote = None;
+
+    for (i, &b) in bytes.iter().enumerate().skip(from) {
+        match (quote, b) {
+            (Some(q), b) if q == b => quote = None,
+            (Some(_), _) => {}
+            (None, b'"' | b'\'') => quote = Some(b),
+            (None, b'>') => return Ok((i + 1, bytes[i - 1] == b'/')),
+            _ => {}
+        }
+    }
+
+    Err(Error::UnclosedRoot)
+}
@@ -1,22 +1,28 @@
 use xmlparser::{self, ElementEnd, Token};
 
 use crate::{
-    builder::{DocumentDbBuilder, InsertAttr, InsertNode, InsertRootElement},
+    builder::{DocumentDbBuilder, InsertAttr, InsertError, InsertNode, InsertRootElement},
     document::{DocumentDb, NodeType},
+    json::JsonConvention,
+    progress::{CancelToken, Monitor, Phase, Progress, PROGRESS_INTERVAL},
+    recover::Recovery,
 };
 
-fn parse_start_event(
+pub(crate) fn parse_start_event<F>(
     local_name: &str,
     prefix: Option<&str>,
     position: usize,
     parser_state: &mut ParserState,
-    tx: &mut crossbeam_channel::Sender<Message>,
+    emit: &mut F,
     node_id_count: &mut usize,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
     let parent_node_id = parser_state.parent_node_id();
 
     if matches!(parser_state.current(), ParserStateValue::Document) {
-        tx.send(Message::InsertRootElement(InsertRootElement::new(
+        emit(Message::InsertRootElement(InsertRootElement::new(
             prefix.map(|x| x.to_owned()),
             Some(local_name.to_string()),
             position,
@@ -36,7 +42,7 @@ fn parse_start_event(
             position,
             parser_state.current_order(),
         );
-        tx.send(Message::InsertNode(node))?;
+        emit(Message::InsertNode(node))?;
 
         *node_id_count += 1;
 
@@ -59,6 +65,7 @@ pub struct ParserState {
     stack: Vec<ParserStateValue>,
     context_order: usize,
     order: Vec<usize>,
+    root_order: usize,
 }
 
 impl ParserState {
@@ -86,6 +93,19 @@ impl ParserState {
         }
     }
 
+    #[inline(always)]
+    pub fn advance_order(&mut self, count: usize) {
+        match self.order.last_mut() {
+            Some(x) => *x += count,
+            None => self.context_order += count,
+        }
+    }
+
+    #[inline(always)]
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
     #[inline(always)]
     pub fn parent_node_id(&self) -> usize {
         match self.current() {
@@ -103,8 +123,17 @@ impl ParserState {
 
     #[inline(always)]
     pub fn pop(&mut self) {
-        self.stack.pop();
-        self.order.pop();
+        let value = self.stack.pop();
+        let order = self.order.pop();
+
+        if let (Some(ParserStateValue::Root), Some(order)) = (value, order) {
+            self.root_order = order;
+        }
+    }
+
+    pub fn reopen_root(&mut self) {
+        self.stack.push(ParserStateValue::Root);
+        self.order.push(self.root_order);
     }
 }
 
@@ -116,27 +145,107 @@ pub enum Error {
     #[error("{0}")]
     Db(#[from] rusqlite::Error),
 
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+
+    #[cfg(feature = "arrow")]
+    #[error("{0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+
+    #[cfg(feature = "arrow")]
+    #[error("{0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[error("{0}")]
+    Deserialize(String),
+
     #[error("{0}")]
     Utf8(#[from] std::str::Utf8Error),
 
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
     #[error("{0}")]
     Channel(#[from] crossbeam_channel::SendError<Message>),
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("aborted before the input was fully parsed")]
+    Aborted,
+
+    #[error("unexpected end of input inside the root element")]
+    UnclosedRoot,
+
+    #[error("database has no meta table, open it with `DocumentDb::open_unversioned`")]
+    Unversioned,
+
+    #[error("database schema version {0} is newer than the supported version {1}")]
+    UnsupportedSchemaVersion(u32, u32),
+
+    #[error("database schema version {0} must be migrated by opening it read-write first")]
+    MigrationRequired(u32),
+
+    #[error("invalid or missing meta value `{0}`")]
+    InvalidMeta(&'static str),
+
+    #[error("option `{0}` does not match the database")]
+    IncompatibleOptions(&'static str),
+
+    #[error("unsupported: {0}")]
+    Unsupported(&'static str),
+
+    #[error("`{0}` is not a valid XML name")]
+    InvalidName(String),
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone)]
 pub struct ParseOptions {
     pub ignore_whitespace: bool,
     pub infer_types: bool,
     pub case_insensitive: bool,
+    pub threads: usize,
+    pub recover: bool,
+    pub html: bool,
+    /// Import the input as JSON using the given convention instead of
+    /// parsing it as XML. Keys that are not valid XML names, such as
+    /// `"first name"` or `"1"`, fail with `Error::InvalidName`. Setting
+    /// both this and `html` fails with `Error::IncompatibleOptions`.
+    pub json: Option<JsonConvention>,
+    pub progress: Option<crossbeam_channel::Sender<Progress>>,
+    pub cancel: Option<CancelToken>,
+}
+
+impl ParseOptions {
+    /// Whether stored text and attribute values still contain their entity
+    /// and character references. html5ever and JSON import store them
+    /// decoded.
+    pub(crate) fn raw_values(&self) -> bool {
+        !self.html && self.json.is_none()
+    }
 }
 
 pub enum Message {
     InsertNode(InsertNode),
     InsertAttr(InsertAttr),
     InsertRootElement(InsertRootElement),
+    InsertError(InsertError),
+    Finish,
+}
+
+impl Message {
+    fn buffer_position(&self) -> usize {
+        match self {
+            Message::InsertNode(x) => x.buffer_position(),
+            Message::InsertAttr(x) => x.buffer_position(),
+            Message::InsertRootElement(x) => x.buffer_position(),
+            Message::InsertError(x) => x.buffer_position(),
+            Message::Finish => 0,
+        }
+    }
 }
 
-fn mutate_text(text: &str, options: &ParseOptions) -> String {
+pub(crate) fn mutate_text(text: &str, options: &ParseOptions) -> String {
     match (options.ignore_whitespace, options.case_insensitive) {
         (true, true) => text.trim().to_lowercase(),
         (true, false) => text.trim().to_string(),
@@ -145,229 +254,393 @@ fn mutate_text(text: &str, options: &ParseOptions) -> String {
     }
 }
 
-pub(crate) fn parse(doc_db: DocumentDb, input: &str) -> Result<DocumentDb, Error> {
-    let options = doc_db.options;
+pub(crate) fn write_message(
+    db: &DocumentDbBuilder<'_>,
+    msg: Message,
+) -> Result<(), rusqlite::Error> {
+    match msg {
+        Message::InsertNode(msg) => db.insert_node(msg),
+        Message::InsertAttr(msg) => db.insert_attr(msg),
+        Message::InsertRootElement(msg) => db.insert_root_element(msg),
+        Message::InsertError(msg) => db.insert_error(msg),
+        Message::Finish => Ok(()),
+    }
+}
+
+pub(crate) fn parse(mut doc_db: DocumentDb, input: &str) -> Result<DocumentDb, Error> {
+    // Progress and cancellation only apply to this parse, so they are not
+    // kept in the options held by the finished document.
+    let monitor = Monitor {
+        progress: doc_db.options.progress.take(),
+        cancel: doc_db.options.cancel.take(),
+    };
+    let options = doc_db.options.clone();
+
+    if options.html && options.json.is_some() {
+        return Err(Error::IncompatibleOptions("json"));
+    }
+
+    if options.html {
+        return crate::html::parse(doc_db, input, monitor);
+    }
+
+    if let Some(convention) = options.json {
+        return crate::json::parse(doc_db, input, convention, monitor);
+    }
+
+    // Recovery needs to see the whole document in order, so it is never split.
+    if options.threads > 1 && !options.recover {
+        return crate::parallel::parse(doc_db, input, monitor);
+    }
 
-    let (mut tx, rx) = crossbeam_channel::bounded(1000000);
+    let (tx, rx) = crossbeam_channel::bounded(1000000);
 
+    let writer_monitor = monitor.clone();
+    let bytes_total = input.len();
     let handle = std::thread::spawn(move || {
         let rx = rx;
+        let monitor = writer_monitor;
 
         let mut doc_db = doc_db;
         let db = DocumentDbBuilder::new(doc_db.conn.transaction().unwrap(), options.infer_types);
+        let mut finished = false;
+        let mut messages = 0usize;
+        let mut count = 0usize;
 
         loop {
             let msg = match rx.recv() {
+                Ok(Message::Finish) => {
+                    finished = true;
+                    break;
+                }
                 Ok(msg) => msg,
                 Err(_) => {
                     break;
                 }
             };
 
-            match msg {
-                Message::InsertNode(msg) => {
-                    db.insert_node(msg).map_err(|e| {
-                        eprintln!("{e:?}");
-                        e
-                    })?;
-                }
-                Message::InsertAttr(msg) => {
-                    db.insert_attr(msg).map_err(|e| {
-                        eprintln!("{e:?}");
-                        e
-                    })?;
-                }
-                Message::InsertRootElement(msg) => {
-                    db.insert_root_element(msg).map_err(|e| {
-                        eprintln!("{e:?}");
-                        e
-                    })?;
-                }
+            if let Message::InsertNode(_) = msg {
+                count += 1;
+            }
+
+            messages += 1;
+            if messages % PROGRESS_INTERVAL == 0 {
+                monitor.check()?;
+                monitor.report(
+                    Phase::Insert,
+                    msg.buffer_position(),
+                    bytes_total,
+                    count,
+                    None,
+                );
             }
+
+            write_message(&db, msg).map_err(|e| {
+                eprintln!("{e:?}");
+                e
+            })?;
+        }
+
+        // Dropping the uncommitted transaction rolls back everything inserted.
+        if !finished {
+            return Err(Error::Aborted);
         }
 
-        db.add_indexes().unwrap();
-        db.commit().unwrap();
+        monitor.check()?;
+        monitor.report(Phase::Index, bytes_total, bytes_total, count, Some(count));
+        db.add_indexes()?;
+        db.add_paths()?;
+        db.commit()?;
+        monitor.report(Phase::Done, bytes_total, bytes_total, count, Some(count));
 
         Ok::<_, Error>(doc_db)
     });
 
     let mut parser_state = ParserState::default();
     let mut node_id_count = 2usize;
+    let mut emit = |msg| tx.send(msg).map_err(Error::from);
+
+    let result = (|| {
+        let mut last_report = 0;
+
+        let mut recovery = options.recover.then(Recovery::default);
+        let mut tokenizer = xmlparser::Tokenizer::from(input);
+        let mut position = 0;
+
+        while let Some(token) = tokenizer.next() {
+            let token = match (token, recovery.as_mut()) {
+                (Ok(token), _) => token,
+                (Err(e), Some(recovery)) => {
+                    position = recovery.skip(
+                        e,
+                        input,
+                        position,
+                        &mut parser_state,
+                        &mut node_id_count,
+                        &options,
+                        &mut emit,
+                    )?;
+                    tokenizer = xmlparser::Tokenizer::from_fragment(input, position..input.len());
+                    continue;
+                }
+                (Err(e), None) => return Err(e.into()),
+            };
+            position = token.span().end();
 
-    // let mut buf = Vec::new();
-    for token in xmlparser::Tokenizer::from(input) {
-        let token = token?;
-        let parent_node_id = parser_state.parent_node_id();
-
-        // println!("{:?}", token);
+            let skip = match recovery.as_mut() {
+                Some(recovery) => recovery.check(&token, &mut parser_state, &mut emit)?,
+                None => false,
+            };
 
-        match token {
-            Token::Declaration { .. } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::Declaration,
-                    None,
-                    None,
-                    None, // TODO: merge them together
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
-            }
-            Token::ProcessingInstruction { .. } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::ProcessingInstruction,
-                    None,
-                    None,
-                    None, // TODO: merge them together
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
-            }
-            Token::Comment { text, .. } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::Comment,
-                    None,
-                    None,
-                    Some(if options.ignore_whitespace {
-                        (&*text).trim().to_string()
-                    } else {
-                        text.to_string()
-                    }),
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
-            }
-            Token::DtdStart { .. } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::Doctype,
-                    None,
-                    None,
-                    None, // TODO: merge them together
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
-            }
-            Token::EmptyDtd { .. } => {}
-            Token::EntityDeclaration { .. } => {}
-            Token::DtdEnd { .. } => {}
-            Token::ElementStart {
-                prefix,
-                local,
-                span,
-            } => {
-                let local = mutate_text(&*local, &options);
-                let prefix = if !prefix.is_empty() {
-                    Some(mutate_text(&*prefix, &options))
-                } else {
-                    None
-                };
-                parse_start_event(
-                    &local,
-                    prefix.as_deref(),
-                    span.start(),
+            if skip {
+                // The tokenizer tracks its own depth, which is wrong once a
+                // token has been dropped, so it is restarted as a fragment.
+                tokenizer = xmlparser::Tokenizer::from_fragment(input, position..input.len());
+            } else {
+                handle_token(
+                    token,
                     &mut parser_state,
-                    &mut tx,
                     &mut node_id_count,
+                    &options,
+                    &mut emit,
                 )?;
             }
-            Token::Attribute {
-                prefix,
-                local,
-                value,
-                span,
-            } => {
-                let prefix = if !prefix.is_empty() {
-                    Some(mutate_text(&*prefix, &options))
-                } else {
-                    None
-                };
-
-                let local = if !local.is_empty() {
-                    Some(mutate_text(&*local, &options))
-                } else {
-                    None
-                };
 
-                let value = if !value.is_empty() {
-                    Some(&*value)
-                } else {
-                    None
-                };
-
-                tx.send(Message::InsertAttr(InsertAttr::new(
-                    parent_node_id,
-                    prefix,
-                    local.unwrap_or_default(),
-                    value.map(|x| x.to_string()).unwrap_or_default(),
-                    span.start(),
-                    parser_state.current_order(),
-                )))?;
-                parser_state.increment_order();
-            }
-            Token::ElementEnd { end, .. } => match end {
-                ElementEnd::Open => continue,
-                ElementEnd::Close(_, _) | ElementEnd::Empty => {
-                    parser_state.pop();
-                }
-            },
-            Token::Text { text } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::Text,
-                    None,
-                    None,
-                    Some(if options.ignore_whitespace {
-                        (&*text).trim().to_string()
-                    } else {
-                        text.to_string()
-                    }),
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
-            }
-            Token::Cdata { text, .. } => {
-                tx.send(Message::InsertNode(InsertNode::new(
-                    node_id_count,
-                    parent_node_id,
-                    NodeType::CData,
-                    None,
+            if node_id_count - last_report >= PROGRESS_INTERVAL {
+                last_report = node_id_count;
+                monitor.check()?;
+                monitor.report(
+                    Phase::Tokenize,
+                    position,
+                    bytes_total,
+                    node_id_count - 2,
                     None,
-                    Some(if options.ignore_whitespace {
-                        (&*text).trim().to_string()
-                    } else {
-                        text.to_string()
-                    }),
-                    token.span().start(),
-                    parser_state.current_order(),
-                )))?;
-                node_id_count += 1;
-                parser_state.increment_order();
+                );
             }
         }
-    }
+
+        if let Some(recovery) = recovery.as_mut() {
+            recovery.finish(input.len(), &mut parser_state, &mut emit)?;
+        }
+
+        emit(Message::Finish)
+    })();
 
     drop(tx);
 
-    let doc_db = handle.join().unwrap()?;
+    let written = handle.join().unwrap();
+
+    match (result, written) {
+        // The writer stopped early, so its error is the interesting one.
+        (Err(Error::Channel(_)), Err(e)) => Err(e),
+        (Err(e), _) => Err(e),
+        (Ok(()), written) => written,
+    }
+}
+
+pub(crate) fn emit_text<F>(
+    text: &str,
+    position: usize,
+    parser_state: &mut ParserState,
+    node_id_count: &mut usize,
+    options: &ParseOptions,
+    emit: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
+    emit(Message::InsertNode(InsertNode::new(
+        *node_id_count,
+        parser_state.parent_node_id(),
+        NodeType::Text,
+        None,
+        None,
+        Some(if options.ignore_whitespace {
+            text.trim().to_string()
+        } else {
+            text.to_string()
+        }),
+        position,
+        parser_state.current_order(),
+    )))?;
+    *node_id_count += 1;
+    parser_state.increment_order();
+
+    Ok(())
+}
+
+pub(crate) fn handle_token<F>(
+    token: Token<'_>,
+    parser_state: &mut ParserState,
+    node_id_count: &mut usize,
+    options: &ParseOptions,
+    emit: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
+    let parent_node_id = parser_state.parent_node_id();
+
+    // println!("{:?}", token);
+
+    match token {
+        Token::Declaration { .. } => {
+            emit(Message::InsertNode(InsertNode::new(
+                *node_id_count,
+                parent_node_id,
+                NodeType::Declaration,
+                None,
+                None,
+                None, // TODO: merge them together
+                token.span().start(),
+                parser_state.current_order(),
+            )))?;
+            *node_id_count += 1;
+            parser_state.increment_order();
+        }
+        Token::ProcessingInstruction {
+            target, content, ..
+        } => {
+            emit(Message::InsertNode(InsertNode::new(
+                *node_id_count,
+                parent_node_id,
+                NodeType::ProcessingInstruction,
+                None,
+                None,
+                Some(match content {
+                    Some(content) => format!("{target} {content}"),
+                    None => target.to_string(),
+                }),
+                token.span().start(),
+                parser_state.current_order(),
+            )))?;
+            *node_id_count += 1;
+            parser_state.increment_order();
+        }
+        Token::Comment { text, .. } => {
+            emit(Message::InsertNode(InsertNode::new(
+                *node_id_count,
+                parent_node_id,
+                NodeType::Comment,
+                None,
+                None,
+                Some(if options.ignore_whitespace {
+                    (&*text).trim().to_string()
+                } else {
+                    text.to_string()
+                }),
+                token.span().start(),
+                parser_state.current_order(),
+            )))?;
+            *node_id_count += 1;
+            parser_state.increment_order();
+        }
+        Token::DtdStart { .. } => {
+            emit(Message::InsertNode(InsertNode::new(
+                *node_id_count,
+                parent_node_id,
+                NodeType::Doctype,
+                None,
+                None,
+                None, // TODO: merge them together
+                token.span().start(),
+                parser_state.current_order(),
+            )))?;
+            *node_id_count += 1;
+            parser_state.increment_order();
+        }
+        Token::EmptyDtd { .. } => {}
+        Token::EntityDeclaration { .. } => {}
+        Token::DtdEnd { .. } => {}
+        Token::ElementStart {
+            prefix,
+            local,
+            span,
+        } => {
+            let local = mutate_text(&*local, options);
+            let prefix = if !prefix.is_empty() {
+                Some(mutate_text(&*prefix, options))
+            } else {
+                None
+            };
+            parse_start_event(
+                &local,
+                prefix.as_deref(),
+                span.start(),
+                parser_state,
+                emit,
+                node_id_count,
+            )?;
+        }
+        Token::Attribute {
+            prefix,
+            local,
+            value,
+            span,
+        } => {
+            let prefix = if !prefix.is_empty() {
+                Some(mutate_text(&*prefix, options))
+            } else {
+                None
+            };
+
+            let local = if !local.is_empty() {
+                Some(mutate_text(&*local, options))
+            } else {
+                None
+            };
+
+            let value = if !value.is_empty() {
+                Some(&*value)
+            } else {
+                None
+            };
+
+            emit(Message::InsertAttr(InsertAttr::new(
+                parent_node_id,
+                prefix,
+                local.unwrap_or_default(),
+                value.map(|x| x.to_string()).unwrap_or_default(),
+                span.start(),
+                parser_state.current_order(),
+            )))?;
+            parser_state.increment_order();
+        }
+        Token::ElementEnd { end, .. } => match end {
+            ElementEnd::Open => {}
+            ElementEnd::Close(_, _) | ElementEnd::Empty => {
+                parser_state.pop();
+            }
+        },
+        Token::Text { text } => {
+            emit_text(
+                &text,
+                token.span().start(),
+                parser_state,
+                node_id_count,
+                options,
+                emit,
+            )?;
+        }
+        Token::Cdata { text, .. } => {
+            emit(Message::InsertNode(InsertNode::new(
+                *node_id_count,
+                parent_node_id,
+                NodeType::CData,
+                None,
+                None,
+                Some(if options.ignore_whitespace {
+                    (&*text).trim().to_string()
+                } else {
+                    text.to_string()
+                }),
+                token.span().start(),
+                parser_state.current_order(),
+            )))?;
+            *node_id_count += 1;
+            parser_state.increment_order();
+        }
+    }
 
-    Ok(doc_db)
+    Ok(())
 }
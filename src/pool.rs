@@ -0,0 +1,79 @@
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{DocumentDb, Error};
+
+/// A pool of read-only connections to a finished database, for sharing one
+/// document between threads. Each thread checks out its own `DocumentDb`.
+#[derive(Debug)]
+pub struct DocumentPool {
+    path: PathBuf,
+    idle: Mutex<Vec<DocumentDb>>,
+    max_idle: usize,
+}
+
+impl DocumentPool {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_max_idle(
+            path,
+            std::thread::available_parallelism().map_or(4, |x| x.get()),
+        )
+    }
+
+    pub fn with_max_idle<P: AsRef<Path>>(path: P, max_idle: usize) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let db = DocumentDb::open_read_only(&path)?;
+
+        Ok(Self {
+            path,
+            idle: Mutex::new(vec![db]),
+            max_idle,
+        })
+    }
+
+    pub fn get(&self) -> Result<PooledDocumentDb<'_>, Error> {
+        let db = self.idle.lock().unwrap().pop();
+
+        let db = match db {
+            Some(db) => db,
+            None => DocumentDb::open_read_only(&self.path)?,
+        };
+
+        Ok(PooledDocumentDb {
+            pool: self,
+            db: Some(db),
+        })
+    }
+
+    fn release(&self, db: DocumentDb) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(db);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PooledDocumentDb<'a> {
+    pool: &'a DocumentPool,
+    db: Option<DocumentDb>,
+}
+
+impl Deref for PooledDocumentDb<'_> {
+    type Target = DocumentDb;
+
+    fn deref(&self) -> &Self::Target {
+        self.db.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledDocumentDb<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.pool.release(db);
+        }
+    }
+}
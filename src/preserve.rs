@@ -0,0 +1,146 @@
+use std::{collections::HashMap, io::Write, ops::Range};
+
+use xmlparser::{Token, Tokenizer};
+
+use crate::{DocumentDb, Error, NodeType};
+
+impl DocumentDb {
+    pub fn preserving_to_string(&self, input: &str) -> Result<String, Error> {
+        let mut s = vec![];
+        self.write_preserving(input, &mut s)?;
+        Ok(String::from_utf8(s).expect("invalid UTF-8"))
+    }
+
+    /// Writes `input`, the source the document was parsed from, replacing
+    /// only the text, CDATA, comment and attribute values whose stored value
+    /// has changed since parsing. Everything else is copied byte for byte.
+    ///
+    /// Replacement values are escaped as plain text. Nodes and attributes
+    /// that were added, removed or renamed are not reflected in the output.
+    pub fn write_preserving<W: Write>(&self, input: &str, mut f: W) -> Result<(), Error> {
+        if self.options.html || self.options.json.is_some() {
+            return Err(Error::Unsupported(
+                "preserving output requires XML parser positions",
+            ));
+        }
+
+        let nodes = self.values_by_position(
+            r#"
+                SELECT buffer_position, node_value FROM nodes
+                WHERE node_type IN (?1, ?2, ?3)
+            "#,
+            &[NodeType::Text, NodeType::CData, NodeType::Comment],
+        )?;
+        let attrs = self.values_by_position(
+            r#"
+                SELECT buffer_position, attr_value FROM attrs
+            "#,
+            &[],
+        )?;
+
+        let trim = self.options.ignore_whitespace;
+        let mut last = 0;
+
+        for token in Tokenizer::from(input) {
+            let (range, value) = match token? {
+                Token::Text { text } => {
+                    let stored = match nodes.get(&text.start()) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    let (range, changed) = changed_range(&text, stored, trim);
+                    if !changed {
+                        continue;
+                    }
+                    (range, escape(stored, None))
+                }
+                Token::Cdata { text, span } => {
+                    let stored = match nodes.get(&span.start()) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    let (range, changed) = changed_range(&text, stored, trim);
+                    if !changed {
+                        continue;
+                    }
+                    (range, stored.replace("]]>", "]]]]><![CDATA[>"))
+                }
+                Token::Comment { text, span } => {
+                    let stored = match nodes.get(&span.start()) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    let (range, changed) = changed_range(&text, stored, trim);
+                    if !changed {
+                        continue;
+                    }
+                    (range, stored.replace("--", "- -"))
+                }
+                Token::Attribute { value, span, .. } => {
+                    let stored = match attrs.get(&span.start()) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    if stored == value.as_str() {
+                        continue;
+                    }
+                    let quote = input[..value.start()].chars().next_back();
+                    (value.range(), escape(stored, quote))
+                }
+                _ => continue,
+            };
+
+            f.write_all(input[last..range.start].as_bytes())?;
+            f.write_all(value.as_bytes())?;
+            last = range.end;
+        }
+
+        f.write_all(input[last..].as_bytes())?;
+        Ok(())
+    }
+
+    fn values_by_position(
+        &self,
+        sql: &str,
+        node_types: &[NodeType],
+    ) -> rusqlite::Result<HashMap<usize, String>> {
+        let mut statement = self.conn.prepare(sql)?;
+        let values = statement
+            .query_map(rusqlite::params_from_iter(node_types), |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(values)
+    }
+}
+
+/// The range of `text` to replace with `stored`, and whether it differs from
+/// what was parsed. Whitespace that was trimmed away when parsing is kept.
+fn changed_range(text: &xmlparser::StrSpan<'_>, stored: &str, trim: bool) -> (Range<usize>, bool) {
+    if !trim {
+        return (text.range(), stored != text.as_str());
+    }
+
+    let source = text.trim();
+    let start = text.start() + (text.len() - text.trim_start().len());
+    let end = text.end() - (text.len() - text.trim_end().len());
+    (start..end.max(start), stored != source)
+}
+
+fn escape(input: &str, quote: Option<char>) -> String {
+    let mut s = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '"' if quote == Some('"') => s.push_str("&quot;"),
+            '\'' if quote == Some('\'') => s.push_str("&apos;"),
+            ch => s.push(ch),
+        }
+    }
+    s
+}
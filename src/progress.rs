@@ -0,0 +1,82 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::Error;
+
+pub(crate) const PROGRESS_INTERVAL: usize = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Tokenize,
+    Insert,
+    Index,
+    Redact,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Position in the input being parsed. Redaction has no input buffer, so
+    /// both byte fields are 0 for `Phase::Redact` and the `Done` after it.
+    pub bytes_consumed: usize,
+    pub bytes_total: usize,
+    pub nodes: usize,
+    pub nodes_total: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Monitor {
+    pub progress: Option<crossbeam_channel::Sender<Progress>>,
+    pub cancel: Option<CancelToken>,
+}
+
+impl Monitor {
+    #[inline]
+    pub fn report(
+        &self,
+        phase: Phase,
+        bytes_consumed: usize,
+        bytes_total: usize,
+        nodes: usize,
+        nodes_total: Option<usize>,
+    ) {
+        if let Some(tx) = self.progress.as_ref() {
+            // A full or closed channel must never stall the work being reported on.
+            let _ = tx.try_send(Progress {
+                phase,
+                bytes_consumed,
+                bytes_total,
+                nodes,
+                nodes_total,
+            });
+        }
+    }
+
+    #[inline]
+    pub fn check(&self) -> Result<(), Error> {
+        match self.cancel.as_ref() {
+            Some(x) if x.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use rusqlite::{
+    ffi,
+    types::{ToSqlOutput, ValueRef},
+    Batch, Error, Result, ToSql,
+};
+
+use crate::{document::DocumentDb, model};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(x) => Some(*x as f64),
+            Value::Real(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Text(x) => Some(x.as_bytes()),
+            Value::Blob(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_node_id(&self) -> Option<usize> {
+        self.as_i64().and_then(|x| usize::try_from(x).ok())
+    }
+}
+
+impl From<ValueRef<'_>> for Value {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(x) => Value::Integer(x),
+            ValueRef::Real(x) => Value::Real(x),
+            ValueRef::Text(x) => Value::Text(String::from_utf8_lossy(x).into_owned()),
+            ValueRef::Blob(x) => Value::Blob(x.to_vec()),
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(match self {
+            Value::Null => ValueRef::Null,
+            Value::Integer(x) => ValueRef::Integer(*x),
+            Value::Real(x) => ValueRef::Real(*x),
+            Value::Text(x) => ValueRef::Text(x.as_bytes()),
+            Value::Blob(x) => ValueRef::Blob(x),
+        }))
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Real(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl QueryResult {
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|x| x.eq_ignore_ascii_case(name))
+    }
+
+    /// Every value of the named column, in row order.
+    pub fn column(&self, name: &str) -> Option<Vec<&Value>> {
+        let index = self.column_index(name)?;
+        Some(self.rows.iter().map(|row| &row[index]).collect())
+    }
+
+    /// The nodes referenced by the `node_id` column, or by the first column
+    /// if there is none. `NULL` values and the document node are skipped.
+    pub fn nodes(&self, db: &DocumentDb) -> Result<Vec<model::Node>> {
+        let index = self.column_index("node_id").unwrap_or(0);
+        self.rows
+            .iter()
+            .filter_map(|row| row.get(index).and_then(Value::as_node_id))
+            .filter(|node_id| *node_id != 0)
+            .map(|node_id| db.node(node_id))
+            .collect()
+    }
+}
+
+impl DocumentDb {
+    /// Runs a single read-only statement, binding `params` to `?1`, `?2`
+    /// and so on. Statements that would modify the database are rejected.
+    ///
+    /// The schema is stable within a `schema_version` (see the `meta`
+    /// table):
+    ///
+    /// - `nodes(node_id, parent_node_id, node_order, node_type, node_ns,
+    ///   node_name, node_value, buffer_position[, inferred_type], path_id,
+    ///   depth)`: node 0 is the document and is its own parent, node 1 is the
+    ///   root element. `node_type` is the integer value of
+    ///   [`NodeType`](crate::NodeType) and `node_ns` holds the prefix.
+    ///   `path_id` and `depth` are only set for elements; the root has
+    ///   depth 1.
+    /// - `paths(path_id, path, depth)`: every distinct element path, such as
+    ///   `/catalog/book/title`.
+    /// - `attrs(attr_id, attr_order, attr_ns, attr_name, attr_value,
+    ///   parent_node_id, buffer_position[, inferred_type])`
+    /// - `parse_errors(error_id, parent_node_id, buffer_position, message)`
+    /// - `meta(key, value)`
+    ///
+    /// `inferred_type` only exists when the document was parsed with
+    /// `infer_types`. The `xml_text`, `xml_path`, `xml_serialize` and
+    /// `xml_attr` functions and the `xml_select(node_id, css)` table are
+    /// available too.
+    pub fn query(&self, sql: &str, params: &[Value]) -> Result<QueryResult> {
+        let mut batch = Batch::new(&self.conn, sql);
+        let mut statement = match batch.next()? {
+            Some(x) => x,
+            None => return Err(Error::InvalidQuery),
+        };
+
+        if batch.next()?.is_some() {
+            return Err(Error::MultipleStatement);
+        }
+
+        // SQLite counts attaching another database as read-only.
+        if !statement.readonly() || matches!(first_keyword(sql).as_str(), "ATTACH" | "DETACH") {
+            return Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_READONLY),
+                Some("only read-only statements can be queried".to_string()),
+            ));
+        }
+
+        let columns = statement
+            .column_names()
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        let mut rows = vec![];
+        let mut cursor = statement.query(rusqlite::params_from_iter(params))?;
+        while let Some(row) = cursor.next()? {
+            let mut values = Vec::with_capacity(columns.len());
+            for i in 0..columns.len() {
+                values.push(Value::from(row.get_ref(i)?));
+            }
+            rows.push(values);
+        }
+
+        Ok(QueryResult { columns, rows })
+    }
+
+    /// Runs [`query`](Self::query) and resolves its node ids to nodes.
+    pub fn query_nodes(&self, sql: &str, params: &[Value]) -> Result<Vec<model::Node>> {
+        self.query(sql, params)?.nodes(self)
+    }
+}
+
+/// The first keyword of a statement, skipping whitespace and comments.
+fn first_keyword(sql: &str) -> String {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(x) = rest.strip_prefix("--") {
+            rest = x.split_once('\n').map_or("", |x| x.1);
+        } else if let Some(x) = rest.strip_prefix("/*") {
+            rest = x.split_once("*/").map_or("", |x| x.1);
+        } else {
+            break;
+        }
+    }
+
+    rest.chars()
+        .take_while(|x| x.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
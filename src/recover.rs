@@ -0,0 +1,234 @@
+use xmlparser::{ElementEnd, Token};
+
+use crate::{
+    builder::InsertError,
+    parse::{emit_text, Error, Message, ParseOptions, ParserState, ParserStateValue},
+};
+
+/// Tracks open element names so a lenient parse can repair the structure of
+/// malformed input. Every repair is recorded in the `parse_errors` table.
+#[derive(Debug, Default)]
+pub(crate) struct Recovery {
+    names: Vec<(String, String)>,
+    root: Option<(String, String)>,
+    root_closed: bool,
+    reopened: bool,
+    in_tag: bool,
+}
+
+fn report<F>(
+    emit: &mut F,
+    position: usize,
+    parser_state: &ParserState,
+    message: String,
+) -> Result<(), Error>
+where
+    F: FnMut(Message) -> Result<(), Error>,
+{
+    emit(Message::InsertError(InsertError::new(
+        parser_state.parent_node_id(),
+        position,
+        message,
+    )))
+}
+
+impl Recovery {
+    /// Returns `true` if the token must be skipped.
+    pub fn check<F>(
+        &mut self,
+        token: &Token<'_>,
+        parser_state: &mut ParserState,
+        emit: &mut F,
+    ) -> Result<bool, Error>
+    where
+        F: FnMut(Message) -> Result<(), Error>,
+    {
+        match token {
+            Token::ElementStart {
+                prefix,
+                local,
+                span,
+            } => {
+                let is_document = matches!(parser_state.current(), ParserStateValue::Document);
+
+                if is_document && self.root_closed {
+                    report(
+                        emit,
+                        span.start(),
+                        parser_state,
+                        format!("element `{local}` after the root element"),
+                    )?;
+                    parser_state.reopen_root();
+                    self.names.extend(self.root.clone());
+                    self.reopened = true;
+                }
+
+                let name = (prefix.to_string(), local.to_string());
+                if is_document && self.root.is_none() {
+                    self.root = Some(name.clone());
+                }
+                self.names.push(name);
+                self.in_tag = true;
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Open,
+                ..
+            } => {
+                self.in_tag = false;
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Empty,
+                ..
+            } => {
+                self.in_tag = false;
+                self.names.pop();
+                self.root_closed = self.names.is_empty();
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Close(prefix, local),
+                span,
+            } => {
+                self.in_tag = false;
+
+                let index = self
+                    .names
+                    .iter()
+                    .rposition(|(p, l)| p == prefix.as_str() && l == local.as_str());
+
+                let index = match index {
+                    Some(x) => x,
+                    None => {
+                        report(
+                            emit,
+                            span.start(),
+                            parser_state,
+                            format!("unexpected end tag `{local}`"),
+                        )?;
+                        return Ok(true);
+                    }
+                };
+
+                while self.names.len() > index + 1 {
+                    let (_, name) = self.names.pop().unwrap();
+                    report(
+                        emit,
+                        span.start(),
+                        parser_state,
+                        format!("element `{name}` closed by end tag `{local}`"),
+                    )?;
+                    parser_state.pop();
+                }
+
+                self.names.pop();
+                self.root_closed = self.names.is_empty();
+            }
+            Token::Text { text }
+                if matches!(parser_state.current(), ParserStateValue::Document) =>
+            {
+                // Only reachable after a restart, when the tokenizer no longer
+                // knows it is outside the root element.
+                if !text.trim().is_empty() {
+                    report(
+                        emit,
+                        text.start(),
+                        parser_state,
+                        "text outside the root element".to_string(),
+                    )?;
+                }
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        Ok(false)
+    }
+
+    /// Records a tokenizer error and returns the position to resume from. Bad
+    /// input inside element content is kept as text, the rest of a broken start
+    /// tag is skipped and anything else is dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn skip<F>(
+        &mut self,
+        error: xmlparser::Error,
+        input: &str,
+        position: usize,
+        parser_state: &mut ParserState,
+        node_id_count: &mut usize,
+        options: &ParseOptions,
+        emit: &mut F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(Message) -> Result<(), Error>,
+    {
+        report(emit, position, parser_state, error.to_string())?;
+
+        let from = position + input[position..].chars().next().map_or(0, |c| c.len_utf8());
+        let next = input[from..].find('<').map_or(input.len(), |i| from + i);
+
+        if self.in_tag {
+            self.in_tag = false;
+
+            // The element is already open, so resume after the end of the
+            // broken start tag unless another tag begins first.
+            let end = match input[position..next].find('>') {
+                Some(i) => position + i,
+                None => return Ok(next),
+            };
+
+            if input[..end].ends_with('/') {
+                let (_, name) = self.names.pop().unwrap();
+                report(
+                    emit,
+                    end - 1,
+                    parser_state,
+                    format!("element `{name}` closed by `/>` after a malformed start tag"),
+                )?;
+                parser_state.pop();
+                self.root_closed = self.names.is_empty();
+            }
+
+            return Ok(end + 1);
+        }
+
+        if !matches!(parser_state.current(), ParserStateValue::Document) {
+            emit_text(
+                &input[position..next],
+                position,
+                parser_state,
+                node_id_count,
+                options,
+                emit,
+            )?;
+        }
+
+        Ok(next)
+    }
+
+    pub fn finish<F>(
+        &mut self,
+        position: usize,
+        parser_state: &mut ParserState,
+        emit: &mut F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Message) -> Result<(), Error>,
+    {
+        while let Some((_, name)) = self.names.pop() {
+            // A root element reopened for trailing content was already closed.
+            if self.reopened && self.names.is_empty() {
+                parser_state.pop();
+                break;
+            }
+
+            report(
+                emit,
+                position,
+                parser_state,
+                format!("element `{name}` was not closed"),
+            )?;
+            parser_state.pop();
+        }
+
+        Ok(())
+    }
+}
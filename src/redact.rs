@@ -3,7 +3,11 @@ use std::collections::HashSet;
 use rusqlite::Transaction;
 use uuid::Uuid;
 
-use crate::{model::Node, DocumentDb, InferredType};
+use crate::{
+    model::Node,
+    progress::{Monitor, Phase, PROGRESS_INTERVAL},
+    CancelToken, DocumentDb, Error, InferredType, Progress,
+};
 
 pub struct IgnoreRule {
     pub match_tag: String,
@@ -19,6 +23,8 @@ pub struct Options {
     pub ignore: Vec<IgnoreRule>,
     pub mask: Mask,
     // replace: Replace,
+    pub progress: Option<crossbeam_channel::Sender<Progress>>,
+    pub cancel: Option<CancelToken>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -92,20 +98,25 @@ pub fn redact(
     db: &DocumentDb,
     mut out_db: DocumentDb,
     options: &Options,
-) -> Result<DocumentDb, rusqlite::Error> {
-    eprintln!("Element count: {}", db.element_count()?);
-    eprintln!("Node count: {}", db.node_count()?);
-    eprintln!("Attr count: {}", db.attr_count()?);
+) -> Result<DocumentDb, Error> {
+    let monitor = Monitor {
+        progress: options.progress.clone(),
+        cancel: options.cancel.clone(),
+    };
+    let element_count = db.element_count()?;
 
     let seed = Uuid::new_v4();
 
+    // Nothing is committed unless every element was processed, so a cancelled
+    // redaction leaves `out_db` untouched.
     let tx = out_db.conn.transaction().unwrap();
     let mut i = 0usize;
     for node in db.all_elements().unwrap() {
         i += 1;
 
-        if i % 10000 == 0 {
-            eprintln!("Parsed {i} elements...");
+        if i % PROGRESS_INTERVAL == 0 {
+            monitor.check()?;
+            monitor.report(Phase::Redact, 0, 0, i, Some(element_count));
         }
 
         let matched_rules = options
@@ -185,6 +196,8 @@ pub fn redact(
         }
     }
 
+    monitor.check()?;
     tx.commit().unwrap();
+    monitor.report(Phase::Done, 0, 0, i, Some(element_count));
     Ok(out_db)
 }
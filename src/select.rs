@@ -1,6 +1,9 @@
 use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::str::FromStr;
 
-use cssparser::{ParseError, ToCss};
+use cssparser::{CowRcStr, ParseError, SourceLocation, ToCss, Token};
+use regex::Regex;
 use selectors::attr::{AttrSelectorOperation, CaseSensitivity, NamespaceConstraint};
 use selectors::bloom::CountingBloomFilter;
 use selectors::context::QuirksMode;
@@ -11,9 +14,10 @@ use selectors::parser::{PseudoElement, SelectorParseErrorKind};
 use selectors::{self, matching, OpaqueElement};
 
 use crate::document::DocumentDb;
+use crate::infer::{infer_type, Inferred, InferredType};
 use crate::model;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Selectors;
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -46,15 +50,138 @@ impl Borrow<String> for Value {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+
+    fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+impl FromStr for CompareOp {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "<" => Ok(CompareOp::Lt),
+            "<=" => Ok(CompareOp::Le),
+            ">" => Ok(CompareOp::Gt),
+            ">=" => Ok(CompareOp::Ge),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    name: String,
+    op: CompareOp,
+    value: String,
+    inferred: Inferred,
+}
+
+impl Comparison {
+    fn matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        let ordering = match (infer_type(value), &self.inferred) {
+            (Inferred::String, Inferred::String) => Some(value.cmp(&self.value)),
+            (a, b) => a.compare(b),
+        };
+
+        ordering.is_some_and(|x| self.op.matches(x))
+    }
+}
+
+impl PartialEq for Comparison {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.op == other.op && self.value == other.value
+    }
+}
+
+impl Eq for Comparison {}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum PseudoClass {}
+pub enum PseudoClass {
+    Link,
+    AnyLink,
+    Has {
+        selector: SelectorList<Selectors>,
+        child: bool,
+    },
+    Contains(String),
+    Matches(Pattern),
+    Type(InferredType),
+    Compare(Box<Comparison>),
+}
 
 impl ToCss for PseudoClass {
-    fn to_css<W>(&self, _dest: &mut W) -> std::fmt::Result
+    fn to_css<W>(&self, dest: &mut W) -> std::fmt::Result
     where
         W: std::fmt::Write,
     {
-        unimplemented!()
+        match self {
+            PseudoClass::Link => dest.write_str(":link"),
+            PseudoClass::AnyLink => dest.write_str(":any-link"),
+            PseudoClass::Has { selector, child } => {
+                dest.write_str(":has(")?;
+                if *child {
+                    dest.write_str("> ")?;
+                }
+                selector.to_css(dest)?;
+                dest.write_str(")")
+            }
+            PseudoClass::Contains(text) => {
+                dest.write_str(":contains(")?;
+                cssparser::serialize_string(text, dest)?;
+                dest.write_str(")")
+            }
+            PseudoClass::Matches(pattern) => {
+                dest.write_str(":matches(")?;
+                cssparser::serialize_string(pattern.0.as_str(), dest)?;
+                dest.write_str(")")
+            }
+            PseudoClass::Type(ty) => write!(dest, ":type({})", ty.as_str()),
+            PseudoClass::Compare(x) => {
+                dest.write_str(":compare(")?;
+                cssparser::serialize_string(&x.name, dest)?;
+                dest.write_str(", ")?;
+                cssparser::serialize_string(x.op.as_str(), dest)?;
+                dest.write_str(", ")?;
+                cssparser::serialize_string(&x.value, dest)?;
+                dest.write_str(")")
+            }
+        }
     }
 }
 
@@ -70,7 +197,27 @@ impl NonTSPseudoClass for PseudoClass {
     }
 }
 
-impl PseudoElement for Value {
+/// What a selector returns for each matched element, written as a trailing
+/// pseudo-element such as `item::attr(id)` or `item::text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Projection {
+    Attr(Value),
+    Text,
+}
+
+impl ToCss for Projection {
+    fn to_css<W>(&self, dest: &mut W) -> std::fmt::Result
+    where
+        W: std::fmt::Write,
+    {
+        match self {
+            Projection::Attr(name) => write!(dest, "::attr({})", name.0),
+            Projection::Text => dest.write_str("::text"),
+        }
+    }
+}
+
+impl PseudoElement for Projection {
     type Impl = Selectors;
 }
 
@@ -84,7 +231,7 @@ impl SelectorImpl for Selectors {
     type BorrowedNamespaceUrl = String;
     type BorrowedLocalName = String;
     type NonTSPseudoClass = PseudoClass;
-    type PseudoElement = Value;
+    type PseudoElement = Projection;
 }
 
 #[derive(Clone)]
@@ -149,7 +296,9 @@ impl selectors::Element for ElementRef<'_> {
     }
 
     fn is_html_element_in_html_document(&self) -> bool {
-        false
+        // Names are lowercased when an HTML document is parsed, and this makes
+        // the selector compare against its lowercased names as well.
+        self.db.options.html && self.element.ns.is_none()
     }
 
     fn has_local_name(&self, local_name: &<Self::Impl as SelectorImpl>::BorrowedLocalName) -> bool {
@@ -190,26 +339,71 @@ impl selectors::Element for ElementRef<'_> {
 
     fn match_non_ts_pseudo_class<F>(
         &self,
-        _pc: &<Self::Impl as SelectorImpl>::NonTSPseudoClass,
+        pc: &<Self::Impl as SelectorImpl>::NonTSPseudoClass,
         _context: &mut selectors::context::MatchingContext<Self::Impl>,
         _flags_setter: &mut F,
     ) -> bool
     where
         F: FnMut(&Self, matching::ElementSelectorFlags),
     {
-        false
+        let node_id = self.element.node_id;
+
+        match pc {
+            // Nothing is ever visited, so every link is unvisited.
+            PseudoClass::Link | PseudoClass::AnyLink => self.is_link(),
+            PseudoClass::Has { selector, child } => {
+                let candidates = if *child {
+                    self.db.children(node_id)
+                } else {
+                    self.db.descendents(node_id)
+                }
+                .unwrap();
+
+                matches_any(self.db, &candidates, selector)
+            }
+            PseudoClass::Contains(text) => self.db.text_content(node_id).unwrap().contains(text),
+            PseudoClass::Matches(pattern) => {
+                pattern.0.is_match(&self.db.text_content(node_id).unwrap())
+            }
+            PseudoClass::Type(ty) => self.db.has_text_of_type(node_id, *ty).unwrap(),
+            // An attribute of that name is compared if there is one, otherwise
+            // the text of every child element of that name is tried.
+            PseudoClass::Compare(x) => {
+                match self.db.attr_by_name(node_id, &x.name, None).unwrap() {
+                    Some(attr) => x.matches(&attr.value),
+                    None => self
+                        .db
+                        .children_by_name(node_id, &x.name)
+                        .unwrap()
+                        .iter()
+                        .any(|child| x.matches(&self.db.text_content(child.node_id).unwrap())),
+                }
+            }
+        }
     }
 
+    // A projection is matched against the element it projects from, and is
+    // only applied once that element has been selected.
     fn match_pseudo_element(
         &self,
         _pe: &<Self::Impl as SelectorImpl>::PseudoElement,
         _context: &mut selectors::context::MatchingContext<Self::Impl>,
     ) -> bool {
-        false
+        true
+    }
+
+    fn pseudo_element_originating_element(&self) -> Option<Self> {
+        Some(self.clone())
     }
 
     fn is_link(&self) -> bool {
-        false
+        self.is_html_element_in_html_document()
+            && matches!(self.element.name.as_str(), "a" | "area" | "link")
+            && self
+                .db
+                .attr_by_name(self.element.node_id, "href", None)
+                .unwrap()
+                .is_some()
     }
 
     fn is_html_slot_element(&self) -> bool {
@@ -263,7 +457,7 @@ impl selectors::Element for ElementRef<'_> {
     }
 
     fn is_empty(&self) -> bool {
-        self.db.has_children(self.element.node_id).unwrap()
+        self.db.is_empty_element(self.element.node_id).unwrap()
     }
 
     fn is_root(&self) -> bool {
@@ -271,6 +465,33 @@ impl selectors::Element for ElementRef<'_> {
     }
 }
 
+// This lives outside of `match_non_ts_pseudo_class` because matching from
+// inside that generic method would instantiate itself without end.
+fn matches_any(
+    db: &DocumentDb,
+    elements: &[model::Element],
+    selector: &SelectorList<Selectors>,
+) -> bool {
+    let mut context = matching::MatchingContext::new(
+        matching::MatchingMode::Normal,
+        None,
+        None,
+        QuirksMode::NoQuirks,
+    );
+
+    elements.iter().any(|element| {
+        let r = ElementRef {
+            db,
+            element: Cow::Borrowed(element),
+        };
+
+        selector
+            .0
+            .iter()
+            .any(|s| matching::matches_selector(s, 0, None, &r, &mut context, &mut |_, _| {}))
+    })
+}
+
 struct TheParser;
 
 impl<'i> Parser<'i> for TheParser {
@@ -284,6 +505,253 @@ impl<'i> Parser<'i> for TheParser {
         // This is a pretty nasty hack but gets the job done for now.
         Some(prefix.0.to_string())
     }
+
+    fn parse_non_ts_pseudo_class(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<PseudoClass, ParseError<'i, Self::Error>> {
+        match &*name.to_ascii_lowercase() {
+            "link" => Ok(PseudoClass::Link),
+            "any-link" => Ok(PseudoClass::AnyLink),
+            _ => Err(location.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+
+    fn parse_non_ts_functional_pseudo_class<'t>(
+        &self,
+        name: CowRcStr<'i>,
+        arguments: &mut cssparser::Parser<'i, 't>,
+    ) -> Result<PseudoClass, ParseError<'i, Self::Error>> {
+        match &*name.to_ascii_lowercase() {
+            "has" => {
+                let child = arguments.try_parse(|p| p.expect_delim('>')).is_ok();
+                Ok(PseudoClass::Has {
+                    selector: SelectorList::parse(self, arguments)?,
+                    child,
+                })
+            }
+            "contains" => Ok(PseudoClass::Contains(
+                arguments.expect_string()?.to_string(),
+            )),
+            "matches" => parse_pattern(arguments).map(PseudoClass::Matches),
+            "type" => {
+                let location = arguments.current_source_location();
+                let ident = arguments.expect_ident()?.clone();
+                match ident.parse::<InferredType>() {
+                    Ok(ty) => Ok(PseudoClass::Type(ty)),
+                    Err(_) => {
+                        Err(location
+                            .new_custom_error(SelectorParseErrorKind::UnexpectedIdent(ident)))
+                    }
+                }
+            }
+            "compare" => {
+                let name = arguments.expect_string()?.to_string();
+                arguments.expect_comma()?;
+                let location = arguments.current_source_location();
+                let op = arguments.expect_string()?.clone();
+                let op = op.parse::<CompareOp>().map_err(|_| {
+                    location.new_unexpected_token_error(Token::QuotedString(op.clone()))
+                })?;
+                arguments.expect_comma()?;
+                let value = arguments.expect_string()?.trim().to_string();
+
+                Ok(PseudoClass::Compare(Box::new(Comparison {
+                    name,
+                    op,
+                    inferred: infer_type(&value),
+                    value,
+                })))
+            }
+            _ => Err(arguments.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+
+    fn parse_pseudo_element(
+        &self,
+        location: SourceLocation,
+        name: CowRcStr<'i>,
+    ) -> Result<Projection, ParseError<'i, Self::Error>> {
+        match &*name.to_ascii_lowercase() {
+            "text" => Ok(Projection::Text),
+            _ => Err(location.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+
+    fn parse_functional_pseudo_element<'t>(
+        &self,
+        name: CowRcStr<'i>,
+        arguments: &mut cssparser::Parser<'i, 't>,
+    ) -> Result<Projection, ParseError<'i, Self::Error>> {
+        match &*name.to_ascii_lowercase() {
+            "attr" => Ok(Projection::Attr(Value(
+                arguments.expect_ident_or_string()?.to_string(),
+            ))),
+            _ => Err(arguments.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+}
+
+/// Rewrites comparisons in attribute brackets like `[price > 10.5]`, which are
+/// not valid CSS, into the equivalent `:compare()` pseudo-class.
+fn rewrite_comparisons(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    let mut rewritten = false;
+
+    while let Some(start) = find_bracket(rest) {
+        let inner = &rest[start + 1..];
+        let end = match find_unquoted(inner, |c| c == ']') {
+            Some(x) => x,
+            None => break,
+        };
+        let inner = &inner[..end];
+
+        out.push_str(&rest[..start]);
+        rest = &rest[start + end + 2..];
+
+        let op_start = match find_unquoted(inner, |c| c == '<' || c == '>') {
+            Some(x) => x,
+            None => {
+                out.push('[');
+                out.push_str(inner);
+                out.push(']');
+                continue;
+            }
+        };
+        let op_end = if inner[op_start + 1..].starts_with('=') {
+            op_start + 2
+        } else {
+            op_start + 1
+        };
+
+        let value = inner[op_end..].trim();
+        let value = match (value.chars().next(), value.chars().last()) {
+            (Some(a @ ('"' | '\'')), Some(b)) if a == b && value.len() > 1 => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+
+        let mut args = String::new();
+        for (i, arg) in [inner[..op_start].trim(), &inner[op_start..op_end], value]
+            .iter()
+            .enumerate()
+        {
+            if i > 0 {
+                args.push_str(", ");
+            }
+            cssparser::serialize_string(arg, &mut args).unwrap();
+        }
+
+        out.push_str(":compare(");
+        out.push_str(&args);
+        out.push(')');
+        rewritten = true;
+    }
+
+    if !rewritten {
+        return None;
+    }
+
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Functional pseudo-classes whose arguments are selectors, so comparisons
+/// inside them are rewritten as well.
+const SELECTOR_ARGUMENTS: [&str; 4] = ["has", "is", "not", "where"];
+
+/// Finds the next unquoted `[` at the selector level. The arguments of other
+/// functional pseudo-classes are skipped, since a `:matches()` regex can
+/// contain brackets of its own.
+fn find_bracket(s: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0usize;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match quote {
+            _ if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '(' && depth > 0 => depth += 1,
+            None if c == '(' => {
+                let name = s[..i]
+                    .rsplit(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')
+                    .next()
+                    .unwrap_or_default();
+                if !SELECTOR_ARGUMENTS.contains(&&*name.to_ascii_lowercase()) {
+                    depth = 1;
+                }
+            }
+            None if c == ')' && depth > 0 => depth -= 1,
+            None if c == '[' && depth == 0 => return Some(i),
+            None => {}
+        }
+    }
+
+    None
+}
+
+fn find_unquoted(s: &str, pred: impl Fn(char) -> bool) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if pred(c) => return Some(i),
+            None => {}
+        }
+    }
+
+    None
+}
+
+/// Accepts either a quoted regex or one written as `/pattern/flags`.
+fn parse_pattern<'i>(
+    input: &mut cssparser::Parser<'i, '_>,
+) -> Result<Pattern, ParseError<'i, SelectorParseErrorKind<'i>>> {
+    let location = input.current_source_location();
+
+    let source = match input.try_parse(|p| p.expect_string().map(|x| x.to_string())) {
+        Ok(x) => x,
+        Err(_) => {
+            let start = input.position();
+            while input.next_including_whitespace_and_comments().is_ok() {}
+            let raw = input.slice_from(start).trim();
+
+            match raw.strip_prefix('/').and_then(|x| x.rsplit_once('/')) {
+                Some((pattern, "")) => pattern.to_string(),
+                Some((pattern, flags)) => format!("(?{flags}){pattern}"),
+                None => {
+                    return Err(location.new_unexpected_token_error(Token::QuotedString(raw.into())))
+                }
+            }
+        }
+    };
+
+    match Regex::new(&source) {
+        Ok(x) => Ok(Pattern(x)),
+        Err(_) => Err(location.new_unexpected_token_error(Token::QuotedString(source.into()))),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -294,6 +762,18 @@ pub struct Selector(Vec<SelectorInner>);
 
 impl Selector {
     pub fn new(s: &str) -> Result<Selector, ParseError<SelectorParseErrorKind>> {
+        if let Some(rewritten) = rewrite_comparisons(s) {
+            let mut input = cssparser::ParserInput::new(&rewritten);
+            if let Ok(list) =
+                SelectorList::parse(&TheParser, &mut cssparser::Parser::new(&mut input))
+            {
+                return Ok(Selector(list.0.into_iter().map(SelectorInner).collect()));
+            }
+
+            // The error can't borrow from the rewritten selector, so it is
+            // taken from the original instead, which is just as invalid.
+        }
+
         let mut input = cssparser::ParserInput::new(s);
         match SelectorList::parse(&TheParser, &mut cssparser::Parser::new(&mut input)) {
             Ok(list) => Ok(Selector(list.0.into_iter().map(SelectorInner).collect())),
@@ -395,4 +875,81 @@ impl Selector {
             })
             .collect::<Result<Vec<_>, _>>()
     }
+
+    #[inline]
+    pub fn select_all(&self, db: &DocumentDb) -> Result<Vec<model::Selected>, rusqlite::Error> {
+        self.select_all_from(db, 0)
+    }
+
+    /// Like `match_all_from`, but applies any `::attr(name)` or `::text`
+    /// projection to the matched elements. Elements without the attribute
+    /// are skipped, and `::text` returns each direct text child.
+    pub fn select_all_from(
+        &self,
+        db: &DocumentDb,
+        node_id: usize,
+    ) -> Result<Vec<model::Selected>, rusqlite::Error> {
+        let bloom_filter = CountingBloomFilter::new();
+        let mut context = matching::MatchingContext::new(
+            matching::MatchingMode::Normal,
+            Some(&bloom_filter),
+            None,
+            QuirksMode::NoQuirks,
+        );
+
+        let mut selected = vec![];
+
+        for element in db.descendents(node_id)? {
+            let r = ElementRef {
+                db,
+                element: Cow::Borrowed(&element),
+            };
+
+            // Each selector in a list contributes its own projection once.
+            let mut projections = vec![];
+            for s in self.0.iter() {
+                if !matching::matches_selector(&s.0, 0, None, &r, &mut context, &mut |_, _| {}) {
+                    continue;
+                }
+
+                let projection = s.0.pseudo_element();
+                if !projections.contains(&projection) {
+                    projections.push(projection);
+                }
+            }
+
+            for projection in projections {
+                match projection {
+                    None => selected.push(model::Selected::Element(element.clone())),
+                    Some(Projection::Attr(name)) => {
+                        let name = if db.options.html {
+                            Cow::Owned(name.0.to_ascii_lowercase())
+                        } else {
+                            Cow::Borrowed(&name.0)
+                        };
+
+                        if let Some(attr) = db.attr_by_name(element.node_id, &name, None)? {
+                            selected.push(model::Selected::Attr(attr));
+                        }
+                    }
+                    Some(Projection::Text) => {
+                        for node in db.child_nodes(element.node_id)? {
+                            match node {
+                                model::Node::Text(x) => selected.push(model::Selected::Text(x)),
+                                model::Node::CData(x) => {
+                                    selected.push(model::Selected::Text(model::Text {
+                                        node_id: x.node_id,
+                                        value: x.value,
+                                    }))
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(selected)
+    }
 }
@@ -0,0 +1,59 @@
+//! Building a document from any `Serialize` value.
+//!
+//! Values are mapped the same way as [`JsonConvention::Simple`]: fields named
+//! `@name` become attributes, `#text` becomes the element's text and any
+//! other field becomes child elements, one per item for sequences. A
+//! sequence inside a sequence, such as `Vec<Vec<T>>`, becomes one element per
+//! inner sequence with an `item` child per value. Fields that are `None` are
+//! left out. Map keys have to be valid XML names, so a `HashMap<u32, _>`
+//! fails with `Error::InvalidName`.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{progress::Monitor, DocumentDb, Error, JsonConvention, ParseOptions};
+
+/// Serializes `value` as the content of a root element named `root`. A
+/// sequence becomes repeated `item` children of the root.
+pub fn to_document<T: Serialize + ?Sized>(
+    root: &str,
+    value: &T,
+    options: ParseOptions,
+) -> Result<DocumentDb, Error> {
+    let mut value = serde_json::to_value(value)?;
+    remove_null_fields(&mut value);
+
+    let value = match value {
+        Value::Array(items) => {
+            let mut map = Map::new();
+            map.insert("item".to_string(), Value::Array(items));
+            Value::Object(map)
+        }
+        value => value,
+    };
+
+    let mut map = Map::new();
+    map.insert(root.to_string(), value);
+
+    let mut db = DocumentDb::create_in_memory(ParseOptions {
+        html: false,
+        json: Some(JsonConvention::Simple),
+        ..options
+    })?;
+    let monitor = Monitor {
+        progress: db.options.progress.take(),
+        cancel: db.options.cancel.take(),
+    };
+    crate::json::import(db, &Value::Object(map), JsonConvention::Simple, monitor, 0)
+}
+
+fn remove_null_fields(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, x| !x.is_null());
+            map.values_mut().for_each(remove_null_fields);
+        }
+        Value::Array(items) => items.iter_mut().for_each(remove_null_fields),
+        _ => {}
+    }
+}
@@ -0,0 +1,246 @@
+use std::{borrow::Cow, io::Write};
+
+use cssparser::ParseError;
+use selectors::parser::SelectorParseErrorKind;
+
+use crate::{
+    c14n::decode_references,
+    infer::infer_type,
+    model::{split_name, Element, Selected},
+    DocumentDb, Error, InferredType, Selector,
+};
+
+/// A column of a table export, evaluated against each row element.
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    expr: ColumnExpr,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ColumnExpr {
+    Attr(Option<String>, String),
+    Child(Option<String>, String),
+    Text,
+    Select(Selector),
+}
+
+impl Column {
+    /// `@name` is an attribute of the row element, `> name` the text of its
+    /// first child element with that name and `.` its own text content.
+    /// Anything else is a selector matched among the row's descendants, such
+    /// as `price`, `customer::attr(id)` or `note::text`, and the first match
+    /// is used.
+    pub fn new<'a>(
+        name: impl Into<String>,
+        expr: &'a str,
+    ) -> Result<Column, ParseError<'a, SelectorParseErrorKind<'a>>> {
+        let expr = match expr.trim() {
+            "." => ColumnExpr::Text,
+            x if x.starts_with('@') => {
+                let (ns, name) = split_name(&x[1..]);
+                ColumnExpr::Attr(ns.map(str::to_string), name.to_string())
+            }
+            x if x.starts_with('>') => {
+                let (ns, name) = split_name(x[1..].trim());
+                ColumnExpr::Child(ns.map(str::to_string), name.to_string())
+            }
+            _ => ColumnExpr::Select(Selector::new(expr)?),
+        };
+
+        Ok(Column {
+            name: name.into(),
+            expr,
+        })
+    }
+
+    #[cfg(feature = "arrow")]
+    pub(crate) fn from_expr(name: String, expr: ColumnExpr) -> Column {
+        Column { name, expr }
+    }
+
+    /// A column named after its expression.
+    pub fn parse(expr: &str) -> Result<Column, ParseError<'_, SelectorParseErrorKind<'_>>> {
+        Column::new(expr, expr)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TableConfig {
+    pub delimiter: char,
+    pub header: bool,
+    /// Quote every value that is not a number or boolean by its inferred
+    /// type, so importers can tell text from numbers. Otherwise values are
+    /// only quoted when they need to be.
+    pub typed: bool,
+}
+
+impl TableConfig {
+    pub fn csv() -> Self {
+        TableConfig {
+            delimiter: ',',
+            header: true,
+            typed: false,
+        }
+    }
+
+    pub fn tsv() -> Self {
+        TableConfig {
+            delimiter: '\t',
+            ..TableConfig::csv()
+        }
+    }
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        TableConfig::csv()
+    }
+}
+
+/// A value of a table export with its inferred type.
+#[derive(Debug, Clone)]
+pub(crate) struct Cell {
+    pub value: String,
+    pub ty: InferredType,
+}
+
+impl DocumentDb {
+    pub fn table_to_string(
+        &self,
+        rows: &Selector,
+        columns: &[Column],
+        config: &TableConfig,
+    ) -> rusqlite::Result<String> {
+        let mut s = vec![];
+        match self.write_table(rows, columns, &mut s, config) {
+            Ok(()) => {}
+            Err(Error::Db(e)) => return Err(e),
+            Err(e) => panic!("{e}"),
+        }
+        Ok(String::from_utf8(s).expect("invalid UTF-8"))
+    }
+
+    /// Writes one delimited row per element matching `rows`, with a value
+    /// for each column. Missing values are left empty.
+    pub fn write_table<W: Write>(
+        &self,
+        rows: &Selector,
+        columns: &[Column],
+        mut f: W,
+        config: &TableConfig,
+    ) -> Result<(), Error> {
+        let mut line = String::new();
+
+        if config.header {
+            for (i, column) in columns.iter().enumerate() {
+                if i > 0 {
+                    line.push(config.delimiter);
+                }
+                line.push_str(&quote(&column.name, config.delimiter, false));
+            }
+            line.push_str("\r\n");
+            f.write_all(line.as_bytes())?;
+        }
+
+        for row in self.table_rows(rows, columns)? {
+            line.clear();
+            for (i, cell) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push(config.delimiter);
+                }
+                if let Some(cell) = cell {
+                    let force = config.typed
+                        && !matches!(
+                            cell.ty,
+                            InferredType::Int | InferredType::Float | InferredType::Boolean
+                        );
+                    line.push_str(&quote(&cell.value, config.delimiter, force));
+                }
+            }
+            line.push_str("\r\n");
+            f.write_all(line.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// The cells of every row element, in document order.
+    pub(crate) fn table_rows(
+        &self,
+        rows: &Selector,
+        columns: &[Column],
+    ) -> rusqlite::Result<Vec<Vec<Option<Cell>>>> {
+        self.table_cells(&rows.clone().match_all(self)?, columns)
+    }
+
+    pub(crate) fn table_cells(
+        &self,
+        elements: &[Element],
+        columns: &[Column],
+    ) -> rusqlite::Result<Vec<Vec<Option<Cell>>>> {
+        let mut table = vec![];
+
+        for element in elements {
+            let mut row = Vec::with_capacity(columns.len());
+            for column in columns {
+                row.push(self.cell(element, &column.expr)?);
+            }
+            table.push(row);
+        }
+
+        Ok(table)
+    }
+
+    fn cell(&self, element: &Element, expr: &ColumnExpr) -> rusqlite::Result<Option<Cell>> {
+        let selected = match expr {
+            ColumnExpr::Attr(ns, name) => self
+                .attr_by_name(element.node_id, name, ns.as_deref())?
+                .map(Selected::Attr),
+            ColumnExpr::Child(ns, name) => self
+                .children_by_name(element.node_id, name)?
+                .into_iter()
+                .find(|x| x.ns == *ns)
+                .map(Selected::Element),
+            ColumnExpr::Text => Some(Selected::Element(element.clone())),
+            ColumnExpr::Select(selector) => selector
+                .select_all_from(self, element.node_id)?
+                .into_iter()
+                .next(),
+        };
+
+        let (value, stored_ty) = match selected {
+            None => return Ok(None),
+            Some(Selected::Element(x)) => (self.text_content(x.node_id)?, None),
+            Some(Selected::Attr(x)) if self.options.infer_types => {
+                let ty = self.attr_inferred_type(x.attr_id)?;
+                (x.value, Some(ty))
+            }
+            Some(Selected::Text(x)) if self.options.infer_types => {
+                let ty = self.inferred_type(x.node_id)?;
+                (x.value, Some(ty))
+            }
+            Some(Selected::Attr(x)) => (x.value, None),
+            Some(Selected::Text(x)) => (x.value, None),
+        };
+
+        let value = if self.options.raw_values() {
+            decode_references(&value).into_owned()
+        } else {
+            value
+        };
+        let ty = stored_ty.unwrap_or_else(|| infer_type(value.trim()).as_type());
+
+        Ok(Some(Cell { value, ty }))
+    }
+}
+
+/// Quotes a value if it contains the delimiter, a quote or a line break, or
+/// if `force` is set, doubling any quotes inside it.
+fn quote(value: &str, delimiter: char, force: bool) -> Cow<'_, str> {
+    if !force && !value.contains(|ch| ch == delimiter || matches!(ch, '"' | '\n' | '\r')) {
+        return Cow::Borrowed(value);
+    }
+
+    Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+}
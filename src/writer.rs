@@ -1,13 +1,14 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fmt::Display,
     io::{self, Write},
     str,
 };
 
 use crate::{
-    model::{Attr, Declaration, Element, Node},
-    DocumentDb,
+    model::{qualified_name, Attr, Declaration, Element, Node, RawNode},
+    DocumentDb, Error, NodeType,
 };
 
 use unic_ucd::GeneralCategory;
@@ -24,6 +25,18 @@ pub struct Config {
     pub max_line_length: usize,
     pub entity_mode: EntityMode,
     pub indent_text_nodes: bool,
+    pub attr_order: AttrOrder,
+    pub quote_style: QuoteStyle,
+    /// Used for the line breaks the writer adds. With `CrLf`, line breaks in
+    /// text, CDATA and comments are converted too.
+    pub line_ending: LineEnding,
+    /// Write elements without content as `<a></a>` instead of `<a/>`.
+    pub expand_empty_elements: bool,
+    /// Start the document with `<?xml version="1.0" encoding="UTF-8"?>` in
+    /// place of any declaration it was parsed with.
+    pub xml_declaration: bool,
+    /// End the output with a line break if it does not already.
+    pub trailing_newline: bool,
 }
 
 impl Config {
@@ -35,6 +48,12 @@ impl Config {
             max_line_length: 120,
             entity_mode: EntityMode::Standard,
             indent_text_nodes: true,
+            attr_order: AttrOrder::Source,
+            quote_style: QuoteStyle::Double,
+            line_ending: LineEnding::Lf,
+            expand_empty_elements: false,
+            xml_declaration: false,
+            trailing_newline: false,
         }
     }
 }
@@ -43,8 +62,10 @@ impl Config {
 pub(crate) struct State<'a> {
     pub is_pretty: bool,
     pub indent: usize,
-    pub node_id: usize,
     pub doc: &'a DocumentDb,
+    /// Extra namespace declarations for the element being printed, used when
+    /// it is printed apart from its ancestors.
+    pub ns_decls: &'a [Attr],
 }
 
 impl<'a> State<'a> {
@@ -53,7 +74,7 @@ impl<'a> State<'a> {
             is_pretty,
             indent: 0,
             doc: document,
-            node_id: 0,
+            ns_decls: &[],
         }
     }
 
@@ -65,8 +86,8 @@ impl<'a> State<'a> {
         State {
             is_pretty: self.is_pretty,
             indent: self.indent + config.indent,
-            node_id: self.node_id,
             doc: self.doc,
+            ns_decls: self.ns_decls,
         }
     }
 
@@ -74,17 +95,8 @@ impl<'a> State<'a> {
         State {
             is_pretty: false,
             indent: 0,
-            node_id: self.node_id,
-            doc: self.doc,
-        }
-    }
-
-    fn with_node_id(&self, node_id: usize) -> Self {
-        State {
-            is_pretty: self.is_pretty,
-            indent: self.indent,
-            node_id,
             doc: self.doc,
+            ns_decls: self.ns_decls,
         }
     }
 }
@@ -93,7 +105,7 @@ impl Print<Config, State<'_>> for Declaration {
     fn print(
         &self,
         f: &mut dyn Write,
-        _config: &Config,
+        config: &Config,
         context: &State<'_>,
     ) -> std::io::Result<()> {
         write!(f, "<?xml")?;
@@ -101,7 +113,7 @@ impl Print<Config, State<'_>> for Declaration {
         write!(f, "?>")?;
 
         if context.is_pretty {
-            writeln!(f)?;
+            newline(f, config)?;
         }
 
         Ok(())
@@ -145,11 +157,7 @@ impl Print<Config, State<'_>> for DocumentDb {
         // if let Some(decl) = self.decl.as_ref() {
         //     Print::print(decl, f, config, context)?;
         // }
-        for node in self.child_nodes(0).unwrap() {
-            node.print(f, config, &context.with_node_id(node.node_id()))?;
-        }
-
-        Ok(())
+        write_tree(f, config, context, 0).map_err(into_io_error)
     }
 }
 
@@ -172,49 +180,51 @@ fn fmt_attrs<'a>(
     let mut iter = attrs.iter();
 
     if let Some(x) = iter.next() {
-        write!(
-            f,
-            "{}=\"{}\"",
-            x.name,
-            process_entities(&x.value, config.entity_mode, false, false)
-        )?;
+        write_attr(f, x, config)?;
     }
 
     if let Some(x) = iter.next() {
         if is_newlines {
-            writeln!(f)?;
+            newline(f, config)?;
             write!(f, "{:>indent$}", "", indent = context.indent)?;
         } else {
             write!(f, " ")?;
         }
-        write!(
-            f,
-            "{}=\"{}\"",
-            x.name,
-            process_entities(&x.value, config.entity_mode, false, false)
-        )?;
+        write_attr(f, x, config)?;
     } else {
         return Ok(());
     }
 
     for x in iter {
         if is_newlines {
-            writeln!(f)?;
+            newline(f, config)?;
             write!(f, "{:>indent$}", "", indent = context.indent)?;
         } else {
             write!(f, " ")?;
         }
-        write!(
-            f,
-            "{}=\"{}\"",
-            x.name,
-            process_entities(&x.value, config.entity_mode, false, false)
-        )?;
+        write_attr(f, x, config)?;
     }
 
     Ok(())
 }
 
+fn write_attr(f: &mut dyn Write, attr: &Attr, config: &Config) -> io::Result<()> {
+    let quote = config.quote_style.as_char();
+    write!(
+        f,
+        "{}={quote}{}{quote}",
+        qualified_name(attr.ns.as_deref(), &attr.name),
+        process_entities(&attr.value, config.entity_mode, false, false)
+    )
+}
+
+fn is_ns_decl(attr: &Attr) -> bool {
+    match &attr.ns {
+        Some(ns) => ns == "xmlns",
+        None => attr.name == "xmlns",
+    }
+}
+
 impl Print<Config, State<'_>> for Element {
     fn print(
         &self,
@@ -222,117 +232,318 @@ impl Print<Config, State<'_>> for Element {
         config: &Config,
         context: &State<'_>,
     ) -> std::io::Result<()> {
-        let nodes = context.doc.child_nodes(self.node_id).unwrap();
-
-        if nodes.is_empty() {
-            let attrs = context.doc.attrs(self.node_id).unwrap();
-            if !attrs.is_empty() {
-                write!(f, "{:>indent$}<{}", "", self.name, indent = context.indent)?;
-                let line_length = &self.name.len()
-                    + 2
-                    + attrs.iter().take(1).fold(0usize, |acc, attr| {
-                        acc + attr.name.len() + attr.value.len() + 4
-                    });
-                let is_newlines = context.is_pretty && line_length > config.max_line_length;
-                if is_newlines {
-                    writeln!(f)?;
+        write_tree(f, config, context, self.node_id).map_err(into_io_error)
+    }
+}
+
+/// Attributes are fetched for this many nodes at a time.
+const BATCH_SIZE: usize = 500;
+
+/// An element whose end tag has not been written yet.
+struct OpenElement {
+    name: String,
+    depth: usize,
+    is_pretty: bool,
+    indent: usize,
+    has_text: bool,
+}
+
+impl OpenElement {
+    fn child_context<'a>(&self, config: &Config, doc: &'a DocumentDb) -> State<'a> {
+        let context = State {
+            is_pretty: self.is_pretty,
+            indent: self.indent,
+            doc,
+            ns_decls: &[],
+        };
+
+        if self.has_text && !config.indent_text_nodes {
+            context.without_pretty()
+        } else {
+            context.with_indent(config)
+        }
+    }
+
+    fn close(&self, f: &mut dyn Write, config: &Config) -> io::Result<()> {
+        if (config.indent_text_nodes || !self.has_text) && self.is_pretty {
+            write!(f, "{:>indent$}</{}>", "", self.name, indent = self.indent)?;
+            newline(f, config)?;
+        } else {
+            write!(f, "</{}>", self.name)?;
+            if self.is_pretty {
+                newline(f, config)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct TreeRow {
+    node: Node,
+    depth: usize,
+    has_children: bool,
+    has_text: bool,
+}
+
+/// Writes `node_id` and everything below it, or the whole document for node
+/// 0. All nodes come from one query in document order and only the open
+/// elements are kept on a stack, so neither the call stack nor the number of
+/// queries grows with the document.
+pub(crate) fn write_tree(
+    f: &mut dyn Write,
+    config: &Config,
+    context: &State<'_>,
+    node_id: usize,
+) -> Result<(), Error> {
+    let mut f = TrackLastByte {
+        inner: f,
+        last: None,
+    };
+    write_nodes(&mut f, config, context, node_id)?;
+
+    if config.trailing_newline && f.last.is_some_and(|x| x != b'\n') {
+        newline(&mut f, config)?;
+    }
+
+    Ok(())
+}
+
+fn write_nodes(
+    f: &mut dyn Write,
+    config: &Config,
+    context: &State<'_>,
+    node_id: usize,
+) -> Result<(), Error> {
+    let doc = context.doc;
+    // Popping the deepest pending row first, and siblings by node_order,
+    // makes the recursive query walk the tree depth first.
+    let mut statement = doc.conn.prepare_cached(
+        r#"
+        WITH RECURSIVE tree(node_id, node_order, node_type, node_ns, node_name, node_value, depth) AS (
+            SELECT node_id, node_order, node_type, node_ns, node_name, node_value, 0 FROM nodes
+                WHERE node_id = ?1
+            UNION ALL
+            SELECT nodes.node_id, nodes.node_order, nodes.node_type, nodes.node_ns,
+                nodes.node_name, nodes.node_value, tree.depth + 1
+                FROM nodes, tree
+                WHERE nodes.parent_node_id = tree.node_id
+                AND nodes.node_id != 0
+                ORDER BY 7 DESC, 2 ASC
+        )
+        SELECT node_id, node_type, node_ns, node_name, node_value, depth,
+            node_type = ?2 AND EXISTS (
+                SELECT 1 FROM nodes WHERE parent_node_id = tree.node_id AND node_id != 0
+            ),
+            node_type = ?2 AND EXISTS (
+                SELECT 1 FROM nodes WHERE parent_node_id = tree.node_id AND node_type IN (?3, ?4)
+            )
+            FROM tree
+            WHERE node_id != 0
+    "#,
+    )?;
+    let mut rows = statement.query([
+        node_id,
+        NodeType::Element as usize,
+        NodeType::Text as usize,
+        NodeType::CData as usize,
+    ])?;
+
+    let mut stack: Vec<OpenElement> = vec![];
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    let synthetic_declaration = node_id == 0 && config.xml_declaration;
+    if synthetic_declaration {
+        write!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        if context.is_pretty {
+            newline(f, config)?;
+        }
+    }
+
+    loop {
+        while batch.len() < BATCH_SIZE {
+            let row = match rows.next()? {
+                Some(x) => x,
+                None => break,
+            };
+            batch.push(TreeRow {
+                node: RawNode {
+                    node_id: row.get(0)?,
+                    node_type: NodeType::try_from(row.get::<_, u8>(1)?).unwrap(),
+                    ns: row.get(2)?,
+                    name: row.get(3)?,
+                    value: row.get(4)?,
+                }
+                .into(),
+                depth: row.get(5)?,
+                has_children: row.get(6)?,
+                has_text: row.get(7)?,
+            });
+        }
+
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut attrs = batch_attrs(doc, &batch)?;
+
+        for row in batch.drain(..) {
+            while stack.last().is_some_and(|x| x.depth >= row.depth) {
+                stack.pop().unwrap().close(f, config)?;
+            }
+
+            let child_context = stack.last().map(|x| x.child_context(config, doc));
+            let context = child_context.as_ref().unwrap_or(context);
+
+            let element = match row.node {
+                Node::Element(x) => x,
+                Node::Declaration(_) if synthetic_declaration => continue,
+                node => {
+                    node.print(f, config, context)?;
+                    continue;
+                }
+            };
+
+            let mut element_attrs = attrs.remove(&element.node_id).unwrap_or_default();
+            if stack.is_empty() && !context.ns_decls.is_empty() {
+                element_attrs.splice(0..0, context.ns_decls.iter().cloned());
+            }
+            match config.attr_order {
+                AttrOrder::Source => {}
+                AttrOrder::Alphabetical => element_attrs.sort_by(|a, b| {
+                    qualified_name(a.ns.as_deref(), &a.name)
+                        .cmp(&qualified_name(b.ns.as_deref(), &b.name))
+                }),
+                AttrOrder::NamespacesFirst => element_attrs.sort_by_key(|x| !is_ns_decl(x)),
+            }
+
+            let name = qualified_name(element.ns.as_deref(), &element.name);
+            write_start_tag(f, &name, config, context, &element_attrs)?;
+
+            if !row.has_children {
+                if config.expand_empty_elements {
+                    if element_attrs.is_empty() {
+                        write!(f, "{:>indent$}<{}>", "", name, indent = context.indent)?;
+                    } else {
+                        write!(f, ">")?;
+                    }
+                    write!(f, "</{}>", name)?;
+                } else if element_attrs.is_empty() {
                     write!(
                         f,
-                        "{:>indent$}",
+                        "{:>indent$}<{:>end_pad$}/>",
                         "",
-                        indent = context.indent + config.indent
+                        name,
+                        indent = context.indent,
+                        end_pad = config.end_pad
                     )?;
                 } else {
-                    write!(f, " ")?;
-                }
-                fmt_attrs(f, &self.name, config, context, &attrs)?;
-                write!(f, "{:>end_pad$}/>", "", end_pad = config.end_pad)?;
-                if context.is_pretty {
-                    writeln!(f)?;
+                    write!(f, "{:>end_pad$}/>", "", end_pad = config.end_pad)?;
                 }
-                return Ok(());
-            } else {
-                write!(
-                    f,
-                    "{:>indent$}<{:>end_pad$}/>",
-                    "",
-                    self.name,
-                    indent = context.indent,
-                    end_pad = config.end_pad
-                )?;
                 if context.is_pretty {
-                    writeln!(f)?;
+                    newline(f, config)?;
                 }
-                return Ok(());
+                continue;
             }
-        }
 
-        let has_text = nodes
-            .iter()
-            .any(|x| matches!(x, Node::Text(_) | Node::CData(_)));
-
-        let attrs = context.doc.attrs(self.node_id).unwrap();
-        if !attrs.is_empty() {
-            write!(f, "{:>indent$}<{}", "", self.name, indent = context.indent)?;
-            let line_length = &self.name.len()
-                + 2
-                + attrs.iter().take(1).fold(0usize, |acc, attr| {
-                    acc + attr.name.len() + attr.value.len() + 4
-                });
-            let is_newlines = context.is_pretty && line_length > config.max_line_length;
-            if is_newlines {
-                writeln!(f)?;
-                write!(
-                    f,
-                    "{:>indent$}",
-                    "",
-                    indent = context.indent + config.indent
-                )?;
+            if element_attrs.is_empty() {
+                write!(f, "{:>indent$}<{}>", "", name, indent = context.indent)?;
             } else {
-                write!(f, " ")?;
+                write!(f, ">")?;
             }
-            fmt_attrs(f, &self.name, config, context, &attrs)?;
-            write!(f, ">")?;
-            if (config.indent_text_nodes || !has_text) && context.is_pretty {
-                writeln!(f)?;
-            }
-        } else {
-            write!(f, "{:>indent$}<{}>", "", self.name, indent = context.indent)?;
-            if (config.indent_text_nodes || !has_text) && context.is_pretty {
-                writeln!(f)?;
+            if (config.indent_text_nodes || !row.has_text) && context.is_pretty {
+                newline(f, config)?;
             }
+
+            stack.push(OpenElement {
+                name: name.into_owned(),
+                depth: row.depth,
+                is_pretty: context.is_pretty,
+                indent: context.indent,
+                has_text: row.has_text,
+            });
         }
+    }
 
-        let child_context = {
-            if has_text && !config.indent_text_nodes {
-                context.without_pretty()
-            } else {
-                context.with_indent(config)
-            }
-        };
+    while let Some(x) = stack.pop() {
+        x.close(f, config)?;
+    }
 
-        for child in nodes.iter() {
-            child.print(f, config, &child_context.with_node_id(child.node_id()))?;
-        }
+    Ok(())
+}
 
-        if (config.indent_text_nodes || !has_text) && context.is_pretty {
-            write!(
-                f,
-                "{:>indent$}</{}>",
-                "",
-                self.name,
-                indent = context.indent
-            )?;
+/// Writes the start of an element's start tag with its attributes, up to the
+/// closing `>` or `/>`. Nothing is written for an element without attributes.
+fn write_start_tag(
+    f: &mut dyn Write,
+    name: &str,
+    config: &Config,
+    context: &State<'_>,
+    attrs: &[Attr],
+) -> io::Result<()> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
 
-            writeln!(f)?;
-        } else {
-            write!(f, "</{}>", self.name)?;
-            if context.is_pretty {
-                writeln!(f)?;
-            }
-        }
+    write!(f, "{:>indent$}<{}", "", name, indent = context.indent)?;
+    let line_length = name.len()
+        + 2
+        + attrs.iter().take(1).fold(0usize, |acc, attr| {
+            acc + attr.name.len() + attr.value.len() + 4
+        });
+    let is_newlines = context.is_pretty && line_length > config.max_line_length;
+    if is_newlines {
+        newline(f, config)?;
+        write!(
+            f,
+            "{:>indent$}",
+            "",
+            indent = context.indent + config.indent
+        )?;
+    } else {
+        write!(f, " ")?;
+    }
+    fmt_attrs(f, name, config, context, attrs)
+}
 
-        Ok(())
+/// The attributes of the elements in a batch, in `attr_order`.
+fn batch_attrs(doc: &DocumentDb, batch: &[TreeRow]) -> rusqlite::Result<HashMap<usize, Vec<Attr>>> {
+    let placeholders = vec!["?"; BATCH_SIZE].join(", ");
+    let mut statement = doc.conn.prepare_cached(&format!(
+        r#"
+        SELECT parent_node_id, attr_id, attr_ns, attr_name, attr_value FROM attrs
+            WHERE parent_node_id IN ({placeholders})
+            ORDER BY parent_node_id, attr_order
+    "#
+    ))?;
+
+    // Unused parameters stay NULL, which matches nothing.
+    let ids = batch
+        .iter()
+        .filter(|x| matches!(x.node, Node::Element(_)))
+        .map(|x| Some(x.node.node_id()))
+        .chain(std::iter::repeat(None))
+        .take(BATCH_SIZE);
+
+    let mut attrs: HashMap<usize, Vec<Attr>> = HashMap::new();
+    let mut rows = statement.query(rusqlite::params_from_iter(ids))?;
+    while let Some(row) = rows.next()? {
+        attrs.entry(row.get(0)?).or_default().push(Attr {
+            attr_id: row.get(1)?,
+            ns: row.get(2)?,
+            name: row.get(3)?,
+            value: row.get(4)?,
+        });
+    }
+
+    Ok(attrs)
+}
+
+/// `Print` can only report I/O errors, so database errors are wrapped.
+fn into_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::other(e),
     }
 }
 
@@ -355,11 +566,14 @@ impl Print<Config, State<'_>> for Node {
             write!(
                 f,
                 "{}",
-                &*process_entities(&t.value, config.entity_mode, true, true)
+                line_breaks(
+                    &process_entities(&t.value, config.entity_mode, true, true),
+                    config
+                )
             )?;
 
             if config.indent_text_nodes && context.is_pretty {
-                writeln!(f)?;
+                newline(f, config)?;
             }
 
             return Ok(());
@@ -370,10 +584,10 @@ impl Print<Config, State<'_>> for Node {
                 write!(f, "{:>indent$}", "", indent = context.indent)?;
             }
 
-            write!(f, "<![CDATA[{}]]>", t.value)?;
+            write!(f, "<![CDATA[{}]]>", line_breaks(&t.value, config))?;
 
             if config.indent_text_nodes && context.is_pretty {
-                writeln!(f)?;
+                newline(f, config)?;
             }
 
             return Ok(());
@@ -384,20 +598,24 @@ impl Print<Config, State<'_>> for Node {
         }
 
         match self {
-            Node::ProcessingInstruction(t) => write!(f, "<!{}>", t.value),
+            Node::ProcessingInstruction(t) => write!(f, "<?{}?>", t.value),
             Node::Comment(t) => write!(
                 f,
                 "<!--{}-->",
-                process_entities(&t.value, config.entity_mode, true, true)
+                line_breaks(
+                    &process_entities(&t.value, config.entity_mode, true, true),
+                    config
+                )
             ),
             Node::Declaration(d) => d.print(f, config, context),
+            Node::Doctype(t) => write!(f, "<!DOCTYPE {}>", t.value),
             x => {
                 panic!("What? {:?}", x)
             }
         }?;
 
         if context.is_pretty {
-            writeln!(f)?;
+            newline(f, config)?;
         }
 
         Ok(())
@@ -416,6 +634,63 @@ impl Default for EntityMode {
     }
 }
 
+/// Order of the attributes within a start tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttrOrder {
+    /// As parsed, by `attr_order`.
+    #[default]
+    Source,
+    /// By qualified name.
+    Alphabetical,
+    /// Namespace declarations first, each group in source order.
+    NamespacesFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteStyle {
+    #[default]
+    Double,
+    Single,
+}
+
+impl QuoteStyle {
+    fn as_char(self) -> char {
+        match self {
+            QuoteStyle::Double => '"',
+            QuoteStyle::Single => '\'',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+fn newline(f: &mut dyn Write, config: &Config) -> io::Result<()> {
+    f.write_all(config.line_ending.as_str().as_bytes())
+}
+
+fn line_breaks<'a>(input: &'a str, config: &Config) -> Cow<'a, str> {
+    match config.line_ending {
+        LineEnding::CrLf if input.contains('\n') => {
+            Cow::Owned(input.replace("\r\n", "\n").replace('\n', "\r\n"))
+        }
+        _ => Cow::Borrowed(input),
+    }
+}
+
 fn process_entities(
     input: &str,
     mode: EntityMode,
@@ -469,6 +744,26 @@ fn process_entities(
     }
 }
 
+/// Remembers the last byte written, for `Config::trailing_newline`.
+struct TrackLastByte<'a> {
+    inner: &'a mut dyn Write,
+    last: Option<u8>,
+}
+
+impl Write for TrackLastByte<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 struct FmtWriter<'a, 'b>(&'b mut std::fmt::Formatter<'a>);
 
 impl Write for FmtWriter<'_, '_> {